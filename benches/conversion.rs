@@ -0,0 +1,69 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use prometheus::{histogram_opts, CounterVec, HistogramVec, Opts, Registry};
+use prometheus_reqwest_remote_write::{ConversionOptions, WriteRequest};
+
+/// A registry with `series` counters and `series / 10` histograms of ten buckets each.
+fn registry(series: usize) -> Registry {
+    let registry = Registry::new();
+    let counter = CounterVec::new(
+        Opts::new("http_requests_total", "handled requests"),
+        &["path", "method", "status"],
+    )
+    .unwrap();
+    let histogram = HistogramVec::new(
+        histogram_opts!(
+            "http_request_duration_seconds",
+            "request latency",
+            prometheus::DEFAULT_BUCKETS.to_vec()
+        ),
+        &["path", "method"],
+    )
+    .unwrap();
+    registry.register(Box::new(counter.clone())).unwrap();
+    registry.register(Box::new(histogram.clone())).unwrap();
+    for i in 0..series {
+        let path = format!("/api/resource/{}", i / 10);
+        let status = [
+            "200", "201", "204", "301", "400", "401", "403", "404", "500", "503",
+        ][i % 10];
+        counter.with_label_values(&[&path, "GET", status]).inc();
+        if i % 10 == 0 {
+            histogram
+                .with_label_values(&[&path, "GET"])
+                .observe(i as f64 / 1000.0);
+        }
+    }
+    registry
+}
+
+fn conversion(c: &mut Criterion) {
+    let options =
+        ConversionOptions::new().with_custom_labels(vec![("instance".into(), "host-1".into())]);
+    let mut group = c.benchmark_group("conversion");
+    for series in [1_000, 10_000] {
+        let families = registry(series).gather();
+        group.throughput(Throughput::Elements(series as u64));
+        group.bench_with_input(
+            BenchmarkId::new("from_metric_families", series),
+            &families,
+            |b, families| {
+                b.iter(|| {
+                    WriteRequest::from_metric_families_with_options(families, &options)
+                        .unwrap()
+                        .encode_proto3()
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("encode_metric_families", series),
+            &families,
+            |b, families| {
+                b.iter(|| WriteRequest::encode_metric_families(families, &options).unwrap())
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, conversion);
+criterion_main!(benches);
@@ -0,0 +1,276 @@
+//! Decoding of Prometheus TSDB chunks, as streamed by remote read.
+//!
+//! Only the Gorilla style XOR encoding of float samples is supported. See
+//! `tsdb/chunkenc/xor.go` in the Prometheus repository for the format.
+
+use crate::{DecodeError, Sample};
+
+/// Reads a big-endian bit stream.
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Result<bool, DecodeError> {
+        let byte = self
+            .data
+            .get(self.position / 8)
+            .ok_or(DecodeError::InvalidChunk("unexpected end of chunk"))?;
+        let bit = byte & (0x80 >> (self.position % 8)) != 0;
+        self.position += 1;
+        Ok(bit)
+    }
+
+    fn read_bits(&mut self, n: u8) -> Result<u64, DecodeError> {
+        let mut value = 0u64;
+        for _ in 0..n {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        Ok(value)
+    }
+
+    fn read_uvarint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.read_bits(8)?;
+            value |= (byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DecodeError::InvalidChunk("varint overflows 64 bits"))
+    }
+
+    fn read_varint(&mut self) -> Result<i64, DecodeError> {
+        let zigzag = self.read_uvarint()?;
+        Ok((zigzag >> 1) as i64 ^ -((zigzag & 1) as i64))
+    }
+}
+
+/// Decode the samples of an XOR chunk.
+pub(crate) fn decode_xor_chunk(data: &[u8]) -> Result<Vec<Sample>, DecodeError> {
+    if data.len() < 2 {
+        return Err(DecodeError::InvalidChunk("chunk is missing its header"));
+    }
+    let count = u16::from_be_bytes([data[0], data[1]]) as usize;
+    let mut reader = BitReader::new(&data[2..]);
+    let mut samples = Vec::with_capacity(count);
+    let mut timestamp = 0i64;
+    let mut t_delta = 0i64;
+    let mut value_bits = 0u64;
+    let mut leading = 0u8;
+    let mut trailing = 0u8;
+    for i in 0..count {
+        match i {
+            0 => {
+                timestamp = reader.read_varint()?;
+                value_bits = reader.read_bits(64)?;
+            }
+            1 => {
+                t_delta = reader.read_uvarint()? as i64;
+                timestamp = timestamp
+                    .checked_add(t_delta)
+                    .ok_or(DecodeError::InvalidChunk("timestamp out of range"))?;
+                read_xor_value(&mut reader, &mut value_bits, &mut leading, &mut trailing)?;
+            }
+            _ => {
+                let mut prefix = 0;
+                while prefix < 4 && reader.read_bit()? {
+                    prefix += 1;
+                }
+                let size = match prefix {
+                    0 => 0,
+                    1 => 14,
+                    2 => 17,
+                    3 => 20,
+                    _ => 64,
+                };
+                let mut dod = 0i64;
+                if size != 0 {
+                    let mut bits = reader.read_bits(size)?;
+                    if size != 64 && bits > (1 << (size - 1)) {
+                        bits = bits.wrapping_sub(1 << size);
+                    }
+                    dod = bits as i64;
+                }
+                t_delta = t_delta
+                    .checked_add(dod)
+                    .ok_or(DecodeError::InvalidChunk("timestamp out of range"))?;
+                timestamp = timestamp
+                    .checked_add(t_delta)
+                    .ok_or(DecodeError::InvalidChunk("timestamp out of range"))?;
+                read_xor_value(&mut reader, &mut value_bits, &mut leading, &mut trailing)?;
+            }
+        }
+        samples.push(Sample {
+            value: f64::from_bits(value_bits),
+            timestamp,
+        });
+    }
+    Ok(samples)
+}
+
+fn read_xor_value(
+    reader: &mut BitReader,
+    value_bits: &mut u64,
+    leading: &mut u8,
+    trailing: &mut u8,
+) -> Result<(), DecodeError> {
+    if !reader.read_bit()? {
+        return Ok(());
+    }
+    if reader.read_bit()? {
+        *leading = reader.read_bits(5)? as u8;
+        let mut significant = reader.read_bits(6)? as u8;
+        if significant == 0 {
+            significant = 64;
+        }
+        *trailing = 64u8
+            .checked_sub(*leading + significant)
+            .ok_or(DecodeError::InvalidChunk("invalid xor value width"))?;
+    }
+    let significant = 64 - *leading - *trailing;
+    let bits = reader.read_bits(significant)?;
+    *value_bits ^= bits << *trailing;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// Writes a big-endian bit stream.
+    #[derive(Default)]
+    struct BitWriter {
+        data: Vec<u8>,
+        bits: usize,
+    }
+
+    impl BitWriter {
+        fn write_bit(&mut self, bit: bool) {
+            if self.bits == self.data.len() * 8 {
+                self.data.push(0);
+            }
+            if bit {
+                *self.data.last_mut().unwrap() |= 0x80 >> (self.bits % 8);
+            }
+            self.bits += 1;
+        }
+
+        fn write_bits(&mut self, value: u64, n: u8) {
+            for i in (0..n).rev() {
+                self.write_bit(value >> i & 1 == 1);
+            }
+        }
+
+        fn write_uvarint(&mut self, mut value: u64) {
+            while value >= 0x80 {
+                self.write_bits(value & 0x7f | 0x80, 8);
+                value >>= 7;
+            }
+            self.write_bits(value, 8);
+        }
+    }
+
+    /// Encode samples as an XOR chunk, the way Prometheus does.
+    pub(crate) fn encode_xor_chunk(samples: &[Sample]) -> Vec<u8> {
+        let mut w = BitWriter::default();
+        let (mut t, mut t_delta, mut v) = (0i64, 0i64, 0u64);
+        let (mut leading, mut trailing) = (0xffu8, 0u8);
+        for (i, sample) in samples.iter().enumerate() {
+            let bits = sample.value.to_bits();
+            if i == 0 {
+                w.write_uvarint(((sample.timestamp << 1) ^ (sample.timestamp >> 63)) as u64);
+                w.write_bits(bits, 64);
+            } else {
+                let delta = sample.timestamp.wrapping_sub(t);
+                if i == 1 {
+                    w.write_uvarint(delta as u64);
+                } else {
+                    let dod = delta.wrapping_sub(t_delta);
+                    let fits = |n: u8| -((1 << (n - 1)) - 1) <= dod && dod <= 1 << (n - 1);
+                    match dod {
+                        0 => w.write_bit(false),
+                        _ if fits(14) => {
+                            w.write_bits(0b10, 2);
+                            w.write_bits(dod as u64, 14);
+                        }
+                        _ if fits(17) => {
+                            w.write_bits(0b110, 3);
+                            w.write_bits(dod as u64, 17);
+                        }
+                        _ if fits(20) => {
+                            w.write_bits(0b1110, 4);
+                            w.write_bits(dod as u64, 20);
+                        }
+                        _ => {
+                            w.write_bits(0b1111, 4);
+                            w.write_bits(dod as u64, 64);
+                        }
+                    }
+                }
+                t_delta = delta;
+                let xor = bits ^ v;
+                if xor == 0 {
+                    w.write_bit(false);
+                } else {
+                    w.write_bit(true);
+                    let lead = (xor.leading_zeros() as u8).min(31);
+                    let trail = xor.trailing_zeros() as u8;
+                    if leading != 0xff && lead >= leading && trail >= trailing {
+                        w.write_bit(false);
+                        w.write_bits(xor >> trailing, 64 - leading - trailing);
+                    } else {
+                        (leading, trailing) = (lead, trail);
+                        w.write_bit(true);
+                        w.write_bits(lead as u64, 5);
+                        let significant = 64 - lead - trail;
+                        w.write_bits(significant as u64, 6);
+                        w.write_bits(xor >> trail, significant);
+                    }
+                }
+            }
+            t = sample.timestamp;
+            v = bits;
+        }
+        let mut chunk = (samples.len() as u16).to_be_bytes().to_vec();
+        chunk.extend(w.data);
+        chunk
+    }
+
+    #[test]
+    pub fn decodes_xor_chunks() {
+        let samples = [
+            (1_700_000_000_000, 1.0),
+            (1_700_000_015_000, 1.0),
+            (1_700_000_030_000, 2.5),
+            (1_700_000_045_001, -3.25),
+            (1_700_000_100_000, 1e9),
+            (1_700_010_000_000, 1e9),
+            (1_700_010_000_001, f64::INFINITY),
+        ]
+        .into_iter()
+        .map(|(timestamp, value)| Sample { value, timestamp })
+        .collect::<Vec<_>>();
+        assert_eq!(
+            decode_xor_chunk(&encode_xor_chunk(&samples)).unwrap(),
+            samples
+        );
+    }
+
+    #[test]
+    pub fn refuses_truncated_chunks() {
+        let samples = [Sample {
+            value: 1.0,
+            timestamp: 1,
+        }];
+        let chunk = encode_xor_chunk(&samples);
+        assert!(decode_xor_chunk(&chunk[..chunk.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,326 @@
+//! Sending write requests.
+//!
+//! [`RemoteWriteClient`] owns the endpoint and http client, sends requests and
+//! classifies the response the way the specification asks senders to: 2xx is a
+//! success, 5xx and 429 may be retried, any other status means the data was
+//! rejected and must not be sent again. With a [`RetryPolicy`] it also retries
+//! the former.
+
+use std::time::Instant;
+
+use reqwest::{Client, StatusCode};
+
+use crate::{
+    ProtocolNegotiator, ProtocolVersion, RemoteWriteError, RetryPolicy, WriteRequest, WriteResponse,
+};
+
+/// How a receiver answered a write request.
+#[derive(Debug, Clone)]
+pub enum WriteOutcome {
+    /// The receiver wrote the request (2xx). It may still report a partial write.
+    Success(WriteResponse),
+    /// The receiver failed temporarily (5xx or 429); the request may be sent again.
+    Retryable(WriteResponse),
+    /// The receiver rejected the request (4xx other than 429); sending it again won't help.
+    Rejected(WriteResponse),
+}
+
+impl WriteOutcome {
+    /// Classify a response by its status.
+    pub fn from_response(response: WriteResponse) -> Self {
+        let status = response.status;
+        if status.is_success() {
+            WriteOutcome::Success(response)
+        } else if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            WriteOutcome::Retryable(response)
+        } else {
+            WriteOutcome::Rejected(response)
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        matches!(self, WriteOutcome::Success(_))
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, WriteOutcome::Retryable(_))
+    }
+
+    pub fn response(&self) -> &WriteResponse {
+        match self {
+            WriteOutcome::Success(response)
+            | WriteOutcome::Retryable(response)
+            | WriteOutcome::Rejected(response) => response,
+        }
+    }
+
+    /// The response of a successful write, or [`RemoteWriteError::Status`] with the error body.
+    pub fn into_result(self) -> Result<WriteResponse, RemoteWriteError> {
+        match self {
+            WriteOutcome::Success(response) => Ok(response),
+            WriteOutcome::Retryable(response) | WriteOutcome::Rejected(response) => {
+                Err(RemoteWriteError::Status {
+                    status: response.status,
+                    body: response.body.unwrap_or_default(),
+                })
+            }
+        }
+    }
+}
+
+/// Sends write requests to one remote write endpoint.
+///
+/// Cheap to clone; clones share the http client and what was negotiated with the endpoint.
+#[derive(Debug, Clone)]
+pub struct RemoteWriteClient {
+    client: Client,
+    endpoint: String,
+    user_agent: String,
+    negotiator: ProtocolNegotiator,
+    retry_policy: RetryPolicy,
+}
+
+impl RemoteWriteClient {
+    /// A client sending Remote Write 1.0 requests to `endpoint`.
+    pub fn new(client: Client, endpoint: &str, user_agent: &str) -> Self {
+        Self {
+            client,
+            endpoint: endpoint.to_string(),
+            user_agent: user_agent.to_string(),
+            negotiator: ProtocolNegotiator::with_preferred(ProtocolVersion::V1),
+            retry_policy: RetryPolicy::never(),
+        }
+    }
+
+    /// Pick the protocol version through `negotiator`, e.g. [`ProtocolNegotiator::new`]
+    /// to try Remote Write 2.0 and fall back to 1.0.
+    pub fn with_negotiator(mut self, negotiator: ProtocolNegotiator) -> Self {
+        self.negotiator = negotiator;
+        self
+    }
+
+    /// Retry retryable responses and failures to reach the receiver according to
+    /// `retry_policy`. By default requests are sent only once.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// The protocol version the next send will use.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.negotiator.version_for(&self.endpoint)
+    }
+
+    /// Send `request` and classify the response.
+    ///
+    /// Only failures to build or send the request are errors; an unsuccessful
+    /// status is reported as a [`WriteOutcome`], with the response body read.
+    ///
+    /// 5xx and 429 responses and transport errors are retried as the retry policy
+    /// allows, waiting as long as the receiver asks with `Retry-After`, up to
+    /// [`RetryPolicy::max_retry_after`], or backing off otherwise. Other 4xx responses are never retried. When the policy gives
+    /// up, the last outcome or error is returned.
+    pub async fn send(&self, request: WriteRequest) -> Result<WriteOutcome, RemoteWriteError> {
+        let policy = &self.retry_policy;
+        let started = Instant::now();
+        let mut attempt = 1;
+        loop {
+            let result = self.send_once(request.clone()).await;
+            let delay = match &result {
+                Ok(WriteOutcome::Retryable(response)) => match response.retry_after {
+                    Some(retry_after) => retry_after.min(policy.max_retry_after),
+                    None => policy.jittered_backoff(attempt),
+                },
+                Err(RemoteWriteError::Transport(_)) => policy.jittered_backoff(attempt),
+                _ => return result,
+            };
+            let past_deadline = policy
+                .deadline
+                .is_some_and(|deadline| started.elapsed().saturating_add(delay) > deadline);
+            if attempt >= policy.max_attempts || past_deadline {
+                return result;
+            }
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    async fn send_once(&self, request: WriteRequest) -> Result<WriteOutcome, RemoteWriteError> {
+        let response = self
+            .negotiator
+            .send(&self.client, &self.endpoint, &self.user_agent, request)
+            .await?;
+        Ok(WriteOutcome::from_response(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::write_request;
+    use crate::CONTENT_TYPE;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+    use wiremock::{
+        matchers::{header, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    #[tokio::test]
+    pub async fn classifies_responses() {
+        for (status, success, retryable) in [
+            (204, true, false),
+            (400, false, false),
+            (429, false, true),
+            (503, false, true),
+        ] {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(header("content-type", CONTENT_TYPE))
+                .respond_with(ResponseTemplate::new(status).set_body_string("out of order sample"))
+                .expect(1)
+                .mount(&server)
+                .await;
+            let client = RemoteWriteClient::new(Client::new(), &server.uri(), "test");
+            let outcome = client.send(write_request()).await.unwrap();
+            assert_eq!(outcome.is_success(), success, "{status}");
+            assert_eq!(outcome.is_retryable(), retryable, "{status}");
+            if success {
+                assert_eq!(outcome.response().body, None);
+            } else {
+                assert_eq!(
+                    outcome.response().body.as_deref(),
+                    Some("out of order sample")
+                );
+                assert_eq!(outcome.into_result().unwrap_err().status().unwrap(), status);
+            }
+        }
+    }
+
+    fn retry_policy() -> RetryPolicy {
+        RetryPolicy::new()
+            .with_min_backoff(Duration::from_millis(1))
+            .with_max_backoff(Duration::from_millis(10))
+            .with_max_attempts(5)
+    }
+
+    #[tokio::test]
+    pub async fn retries_until_the_receiver_recovers() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let client = RemoteWriteClient::new(Client::new(), &server.uri(), "test")
+            .with_retry_policy(retry_policy());
+        assert!(client.send(write_request()).await.unwrap().is_success());
+    }
+
+    #[tokio::test]
+    pub async fn gives_up_after_max_attempts() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(3)
+            .mount(&server)
+            .await;
+        let client = RemoteWriteClient::new(Client::new(), &server.uri(), "test")
+            .with_retry_policy(retry_policy().with_max_attempts(3));
+        assert!(client.send(write_request()).await.unwrap().is_retryable());
+    }
+
+    #[tokio::test]
+    pub async fn never_retries_rejections() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let client = RemoteWriteClient::new(Client::new(), &server.uri(), "test")
+            .with_retry_policy(retry_policy());
+        let outcome = client.send(write_request()).await.unwrap();
+        assert_eq!(outcome.response().status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    pub async fn honors_retry_after() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(429).insert_header("retry-after", "1"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let client = RemoteWriteClient::new(Client::new(), &server.uri(), "test")
+            .with_retry_policy(retry_policy());
+        let started = Instant::now();
+        assert!(client.send(write_request()).await.unwrap().is_success());
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    pub async fn caps_retry_after() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503).insert_header("retry-after", "86400"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let client = RemoteWriteClient::new(Client::new(), &server.uri(), "test")
+            .with_retry_policy(retry_policy().with_max_retry_after(Duration::from_millis(10)));
+        let started = Instant::now();
+        assert!(client.send(write_request()).await.unwrap().is_success());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    pub async fn gives_up_when_retrying_would_pass_the_deadline() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(429).insert_header("retry-after", "60"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let client = RemoteWriteClient::new(Client::new(), &server.uri(), "test")
+            .with_retry_policy(retry_policy().with_deadline(Duration::from_secs(5)));
+        let outcome = client.send(write_request()).await.unwrap();
+        assert_eq!(
+            outcome.response().retry_after,
+            Some(Duration::from_secs(60))
+        );
+        assert!(outcome.is_retryable());
+    }
+
+    #[tokio::test]
+    pub async fn retries_transport_errors() {
+        let client = RemoteWriteClient::new(Client::new(), "http://127.0.0.1:1", "test")
+            .with_retry_policy(retry_policy().with_max_attempts(2));
+        assert!(matches!(
+            client.send(write_request()).await,
+            Err(RemoteWriteError::Transport(_))
+        ));
+    }
+}
@@ -0,0 +1,100 @@
+//! Where sample timestamps come from.
+
+use std::{
+    error::Error,
+    fmt,
+    time::{SystemTime, SystemTimeError},
+};
+
+/// A source of the current time, in milliseconds since the Unix epoch.
+///
+/// Metrics without a timestamp of their own are sent at the time the clock reports
+/// when they are converted.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now_ms(&self) -> Result<i64, ClockError>;
+}
+
+/// The system clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> Result<i64, ClockError> {
+        Ok(SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_millis() as i64)
+    }
+}
+
+/// A clock that is always at the same time, for reproducible output in tests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedClock(pub i64);
+
+impl Clock for FixedClock {
+    fn now_ms(&self) -> Result<i64, ClockError> {
+        Ok(self.0)
+    }
+}
+
+/// The clock could not tell the time, e.g. because it is before the Unix epoch.
+#[derive(Debug)]
+pub struct ClockError(Cause);
+
+#[derive(Debug)]
+enum Cause {
+    BeforeEpoch(SystemTimeError),
+    Custom(Box<dyn Error + Send + Sync>),
+}
+
+impl ClockError {
+    /// A failure of a custom [`Clock`], e.g. one reading the time from elsewhere.
+    pub fn new(error: impl Error + Send + Sync + 'static) -> Self {
+        ClockError(Cause::Custom(Box::new(error)))
+    }
+}
+
+impl fmt::Display for ClockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Cause::BeforeEpoch(e) => write!(f, "system clock is before the Unix epoch: {e}"),
+            Cause::Custom(e) => write!(f, "clock failed: {e}"),
+        }
+    }
+}
+
+impl Error for ClockError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.0 {
+            Cause::BeforeEpoch(e) => Some(e),
+            Cause::Custom(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+impl From<SystemTimeError> for ClockError {
+    fn from(value: SystemTimeError) -> Self {
+        ClockError(Cause::BeforeEpoch(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConversionOptions, WriteRequest};
+    use pretty_assertions::assert_eq;
+    use prometheus::{Counter, Registry};
+
+    #[test]
+    pub fn fixed_clock_makes_output_reproducible() {
+        let registry = Registry::new();
+        let counter = Counter::new("my_counter", "an extra description").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        let options = ConversionOptions::new().with_clock(FixedClock(1_700_000_000_000));
+        let first =
+            WriteRequest::from_metric_families_with_options(&registry.gather(), &options).unwrap();
+        let second =
+            WriteRequest::from_metric_families_with_options(&registry.gather(), &options).unwrap();
+        assert_eq!(first.timeseries[0].samples[0].timestamp, 1_700_000_000_000);
+        assert_eq!(first, second);
+    }
+}
@@ -0,0 +1,413 @@
+//! Conversion of metric families into series.
+//!
+//! The labels of a metric are collected and sorted once, borrowing from the metric
+//! family, and shared by every series derived from the metric: buckets, quantiles,
+//! `_sum`, `_count` and `_created` only differ in their name and at most one extra
+//! label. A [`Series`] is either turned into an owned [`TimeSeries`], or encoded
+//! straight into a protobuf `WriteRequest` without building one.
+
+use std::borrow::Cow;
+
+use prometheus::proto::{MetricFamily, MetricType};
+use prost::encoding::{encode_key, encode_varint, encoded_len_varint, key_len, WireType};
+
+use crate::{
+    float::format_float, ConversionOptions, HistogramEncoding, Label, NativeHistogram, Sample,
+    TimeSeries, COUNT_SUFFIX, CREATED_SUFFIX, LABEL_NAME, SUM_SUFFIX, TOTAL_SUFFIX,
+};
+
+/// The value of a series.
+pub(crate) enum Value {
+    Float(f64),
+    Histogram(Box<NativeHistogram>),
+}
+
+/// A series derived from a metric, borrowing the labels it shares with the other series of the metric.
+pub(crate) struct Series<'a> {
+    /// Sorted by name, without the metric name.
+    labels: &'a [(Cow<'a, str>, &'a str)],
+    name: &'a str,
+    /// A label only this series has, such as `le`. Replaces a shared label with the same name.
+    ///
+    /// Always lowercase, so it sorts after `__name__`.
+    extra: Option<(&'static str, &'a str)>,
+    value: Value,
+    timestamp: i64,
+}
+
+impl<'a> Series<'a> {
+    fn float(
+        labels: &'a [(Cow<'a, str>, &'a str)],
+        name: &'a str,
+        extra: Option<(&'static str, &'a str)>,
+        value: f64,
+        timestamp: i64,
+    ) -> Self {
+        Self {
+            labels,
+            name,
+            extra,
+            value: Value::Float(value),
+            timestamp,
+        }
+    }
+
+    /// Call `f` with every label of this series, sorted by name.
+    fn for_each_label(&self, mut f: impl FnMut(&str, &str)) {
+        let mut own = [(LABEL_NAME, self.name)]
+            .into_iter()
+            .chain(self.extra)
+            .peekable();
+        for (name, value) in self.labels {
+            while let Some((own_name, own_value)) = own.next_if(|(n, _)| *n <= name.as_ref()) {
+                f(own_name, own_value);
+            }
+            if self.extra.is_some_and(|(extra, _)| extra == name) {
+                continue;
+            }
+            f(name, value);
+        }
+        own.for_each(|(name, value)| f(name, value));
+    }
+
+    pub(crate) fn into_timeseries(self) -> TimeSeries {
+        let mut labels = Vec::with_capacity(self.labels.len() + 2);
+        self.for_each_label(|name, value| {
+            labels.push(Label {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+        });
+        let (samples, histograms) = match self.value {
+            Value::Float(value) => (
+                vec![Sample {
+                    value,
+                    timestamp: self.timestamp,
+                }],
+                vec![],
+            ),
+            Value::Histogram(histogram) => (vec![], vec![*histogram]),
+        };
+        TimeSeries {
+            labels,
+            samples,
+            histograms,
+            ..Default::default()
+        }
+    }
+
+    /// Encode this series as an entry of the `timeseries` field of a `WriteRequest`.
+    ///
+    /// The output is identical to encoding the [`TimeSeries`] with prost.
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        let mut len = 0;
+        self.for_each_label(|name, value| len += delimited_len(label_len(name, value)));
+        len += match &self.value {
+            Value::Float(value) => delimited_len(sample_len(*value, self.timestamp)),
+            Value::Histogram(histogram) => {
+                delimited_len(prost::Message::encoded_len(histogram.as_ref()))
+            }
+        };
+        encode_key(1, WireType::LengthDelimited, buf);
+        encode_varint(len as u64, buf);
+        self.for_each_label(|name, value| {
+            encode_key(1, WireType::LengthDelimited, buf);
+            encode_varint(label_len(name, value) as u64, buf);
+            encode_str(1, name, buf);
+            encode_str(2, value, buf);
+        });
+        match &self.value {
+            Value::Float(value) => {
+                encode_key(2, WireType::LengthDelimited, buf);
+                encode_varint(sample_len(*value, self.timestamp) as u64, buf);
+                if *value != 0.0 {
+                    prost::encoding::double::encode(1, value, buf);
+                }
+                if self.timestamp != 0 {
+                    prost::encoding::int64::encode(2, &self.timestamp, buf);
+                }
+            }
+            Value::Histogram(histogram) => {
+                prost::encoding::message::encode(4, histogram.as_ref(), buf)
+            }
+        }
+    }
+}
+
+/// Length of a message of `len` bytes, with its key and length prefix.
+fn delimited_len(len: usize) -> usize {
+    1 + encoded_len_varint(len as u64) + len
+}
+
+/// Length of a string field. Empty strings are left out, as prost does.
+fn str_len(tag: u32, s: &str) -> usize {
+    if s.is_empty() {
+        0
+    } else {
+        key_len(tag) + encoded_len_varint(s.len() as u64) + s.len()
+    }
+}
+
+fn encode_str(tag: u32, s: &str, buf: &mut Vec<u8>) {
+    if !s.is_empty() {
+        encode_key(tag, WireType::LengthDelimited, buf);
+        encode_varint(s.len() as u64, buf);
+        buf.extend_from_slice(s.as_bytes());
+    }
+}
+
+fn label_len(name: &str, value: &str) -> usize {
+    str_len(1, name) + str_len(2, value)
+}
+
+fn sample_len(value: f64, timestamp: i64) -> usize {
+    let value_len = if value != 0.0 { 9 } else { 0 };
+    let timestamp_len = if timestamp != 0 {
+        1 + encoded_len_varint(timestamp as u64)
+    } else {
+        0
+    };
+    value_len + timestamp_len
+}
+
+/// Call `emit` with every series of `mf`, converted at `now`.
+pub(crate) fn for_each_series(
+    mf: &MetricFamily,
+    options: &ConversionOptions,
+    now: i64,
+    mut emit: impl FnMut(Series),
+) {
+    let name = mf.name();
+    let field_type = mf.get_field_type();
+    let counter_name = options.counter_name(name);
+    let sum_name = format!("{name}{SUM_SUFFIX}");
+    let count_name = format!("{name}{COUNT_SUFFIX}");
+    let created_name = match field_type {
+        MetricType::COUNTER => Some(name.strip_suffix(TOTAL_SUFFIX).unwrap_or(name)),
+        MetricType::SUMMARY | MetricType::HISTOGRAM => Some(name),
+        _ => None,
+    }
+    .map(|name| format!("{name}{CREATED_SUFFIX}"));
+    let infinity = format_float(f64::INFINITY);
+    for m in mf.get_metric() {
+        let labels = options.series_labels(m);
+        let timestamp = options.sample_timestamp(m, now);
+        match field_type {
+            MetricType::GAUGE => {
+                emit(Series::float(
+                    &labels,
+                    name,
+                    None,
+                    m.get_gauge().value(),
+                    timestamp,
+                ));
+            }
+            MetricType::COUNTER => {
+                emit(Series::float(
+                    &labels,
+                    &counter_name,
+                    None,
+                    m.get_counter().value(),
+                    timestamp,
+                ));
+            }
+            MetricType::UNTYPED => {
+                emit(Series::float(
+                    &labels,
+                    name,
+                    None,
+                    m.untyped.value(),
+                    timestamp,
+                ));
+            }
+            MetricType::SUMMARY => {
+                let summary = m.get_summary();
+                for quantile in summary.get_quantile() {
+                    let q = format_float(quantile.quantile());
+                    emit(Series::float(
+                        &labels,
+                        name,
+                        Some(("quantile", &q)),
+                        quantile.value(),
+                        timestamp,
+                    ));
+                }
+                emit(Series::float(
+                    &labels,
+                    &sum_name,
+                    None,
+                    summary.sample_sum(),
+                    timestamp,
+                ));
+                emit(Series::float(
+                    &labels,
+                    &count_name,
+                    None,
+                    summary.sample_count() as f64,
+                    timestamp,
+                ));
+            }
+            MetricType::HISTOGRAM
+                if options.histogram_encoding == HistogramEncoding::CustomBuckets =>
+            {
+                emit(Series {
+                    labels: &labels,
+                    name,
+                    extra: None,
+                    value: Value::Histogram(Box::new(NativeHistogram::from_classic(
+                        m.get_histogram(),
+                        timestamp,
+                    ))),
+                    timestamp,
+                });
+            }
+            MetricType::HISTOGRAM => {
+                let histogram = m.get_histogram();
+                for bucket in histogram.get_bucket() {
+                    let le = format_float(bucket.upper_bound());
+                    emit(Series::float(
+                        &labels,
+                        name,
+                        Some(("le", &le)),
+                        bucket.cumulative_count() as f64,
+                        timestamp,
+                    ));
+                }
+                emit(Series::float(
+                    &labels,
+                    &sum_name,
+                    None,
+                    histogram.get_sample_sum(),
+                    timestamp,
+                ));
+                emit(Series::float(
+                    &labels,
+                    &count_name,
+                    None,
+                    histogram.get_sample_count() as f64,
+                    timestamp,
+                ));
+                // Registries may already report the `+Inf` bucket; don't send it twice.
+                let has_infinite_bucket = histogram
+                    .get_bucket()
+                    .iter()
+                    .any(|bucket| bucket.upper_bound() == f64::INFINITY);
+                if !has_infinite_bucket {
+                    emit(Series::float(
+                        &labels,
+                        name,
+                        Some(("le", &infinity)),
+                        histogram.get_sample_count() as f64,
+                        timestamp,
+                    ));
+                }
+            }
+        }
+        if let (Some(created_name), Some(created_timestamp)) =
+            (&created_name, options.created_timestamp)
+        {
+            // OpenMetrics `_created` values are in seconds.
+            emit(Series::float(
+                &labels,
+                created_name,
+                None,
+                created_timestamp as f64 / 1000.0,
+                timestamp,
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LabelCollision, WriteRequest};
+    use pretty_assertions::assert_eq;
+    use prometheus::{histogram_opts, CounterVec, GaugeVec, HistogramVec, Opts, Registry};
+
+    fn metric_families() -> Vec<MetricFamily> {
+        let registry = Registry::new();
+        let counter = CounterVec::new(Opts::new("requests", "help"), &["Path", "job"]).unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.with_label_values(&["/", "api"]).inc_by(3.0);
+        counter.with_label_values(&["/health", "api"]).inc();
+        let gauge = GaugeVec::new(Opts::new("in_flight", "help"), &["le"]).unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        gauge.with_label_values(&["shadowed"]).set(0.0);
+        let histogram = HistogramVec::new(
+            histogram_opts!("latency", "help", vec![0.1, 1.0]),
+            &["path"],
+        )
+        .unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        histogram.with_label_values(&["/"]).observe(0.5);
+
+        let mut quantile = prometheus::proto::Quantile::default();
+        quantile.set_quantile(0.99);
+        quantile.set_value(12.0);
+        let mut summary = prometheus::proto::Summary::default();
+        summary.set_quantile(vec![quantile]);
+        summary.set_sample_count(4);
+        summary.set_sample_sum(20.0);
+        let mut metric = prometheus::proto::Metric::default();
+        metric.set_summary(summary);
+        let mut mf = MetricFamily::default();
+        mf.set_name("rpc_duration".to_string());
+        mf.set_field_type(MetricType::SUMMARY);
+        mf.set_metric(vec![metric]);
+
+        let mut mfs = registry.gather();
+        mfs.push(mf);
+        mfs
+    }
+
+    fn sorted(mut req: WriteRequest) -> WriteRequest {
+        req.timeseries.sort_by(|a, b| {
+            let labels = |ts: &TimeSeries| {
+                ts.labels
+                    .iter()
+                    .map(|l| (l.name.clone(), l.value.clone()))
+                    .collect::<Vec<_>>()
+            };
+            labels(a).cmp(&labels(b))
+        });
+        req
+    }
+
+    #[test]
+    pub fn labels_are_sorted_with_the_name_and_extra_label() {
+        let labels = [
+            (Cow::Borrowed("Path"), "/"),
+            (Cow::Borrowed("job"), "api"),
+            (Cow::Borrowed("le"), "shadowed"),
+        ];
+        let series = Series::float(&labels, "latency", Some(("le", "0.5")), 1.0, 0);
+        let mut seen = vec![];
+        series.for_each_label(|name, value| seen.push(format!("{name}={value}")));
+        assert_eq!(
+            seen,
+            vec!["Path=/", "__name__=latency", "job=api", "le=0.5"]
+        );
+    }
+
+    #[test]
+    pub fn encodes_straight_to_protobuf() {
+        let mfs = metric_families();
+        for options in [
+            ConversionOptions::new(),
+            ConversionOptions::new()
+                .with_custom_labels(vec![("job".into(), "custom".into())])
+                .with_label_collision(LabelCollision::Rename)
+                .with_total_suffix(true)
+                .with_created_timestamp(1_600_000_000_000),
+            ConversionOptions::new().with_histogram_encoding(HistogramEncoding::CustomBuckets),
+        ] {
+            let options = options.with_clock(crate::FixedClock(1_700_000_000_000));
+            let expected = WriteRequest::from_metric_families_with_options(&mfs, &options).unwrap();
+            let encoded = WriteRequest::encode_metric_families(&mfs, &options).unwrap();
+            let decoded = WriteRequest::decode_proto3(&encoded).unwrap();
+            assert_eq!(sorted(decoded), sorted(expected.clone()));
+            assert_eq!(encoded.len(), expected.encode_proto3().len());
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Decoding of captured remote write payloads.
+
+use std::fmt;
+
+/// Default upper bound on the decompressed size of a payload, 32 MiB.
+pub const DEFAULT_MAX_DECOMPRESSED_SIZE: usize = 32 * 1024 * 1024;
+
+/// Why a payload could not be decoded.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The payload claims to decompress to more than the allowed size.
+    TooLarge {
+        size: usize,
+        limit: usize,
+    },
+    Snappy(snap::Error),
+    Protobuf(prost::DecodeError),
+    /// A remote read chunk or frame is malformed or uses an unsupported encoding.
+    InvalidChunk(&'static str),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TooLarge { size, limit } => write!(
+                f,
+                "decompressed payload of {size} bytes exceeds the limit of {limit} bytes"
+            ),
+            DecodeError::Snappy(e) => write!(f, "invalid snappy payload: {e}"),
+            DecodeError::Protobuf(e) => write!(f, "invalid protobuf message: {e}"),
+            DecodeError::InvalidChunk(reason) => write!(f, "invalid chunk: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecodeError::TooLarge { .. } | DecodeError::InvalidChunk(_) => None,
+            DecodeError::Snappy(e) => Some(e),
+            DecodeError::Protobuf(e) => Some(e),
+        }
+    }
+}
+
+impl From<snap::Error> for DecodeError {
+    fn from(value: snap::Error) -> Self {
+        DecodeError::Snappy(value)
+    }
+}
+
+impl From<prost::DecodeError> for DecodeError {
+    fn from(value: prost::DecodeError) -> Self {
+        DecodeError::Protobuf(value)
+    }
+}
+
+/// Decompress a snappy block, refusing payloads that would decompress to more than `limit` bytes.
+///
+/// The decompressed length is read from the snappy header before anything is
+/// allocated, so a small malicious payload cannot make us allocate gigabytes.
+pub(crate) fn decompress(buf: &[u8], limit: usize) -> Result<Vec<u8>, DecodeError> {
+    let size = snap::raw::decompress_len(buf)?;
+    if size > limit {
+        return Err(DecodeError::TooLarge { size, limit });
+    }
+    Ok(snap::raw::Decoder::new().decompress_vec(buf)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::write_request;
+    use crate::{v2, RemoteWriteError, WriteRequest};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    pub fn roundtrips_compressed_payloads() {
+        let req = write_request();
+        let decoded =
+            WriteRequest::decode_compressed(&req.clone().encode_compressed().unwrap()).unwrap();
+        assert_eq!(decoded, req);
+
+        let req = v2::Request::from(req);
+        let decoded =
+            v2::Request::decode_compressed(&req.clone().encode_compressed().unwrap()).unwrap();
+        assert_eq!(decoded, req);
+    }
+
+    #[test]
+    pub fn refuses_payloads_over_the_limit() {
+        let payload = snap::raw::Encoder::new()
+            .compress_vec(&vec![0; 1024])
+            .unwrap();
+        match WriteRequest::decode_compressed_with_limit(&payload, 512) {
+            Err(RemoteWriteError::Decode(DecodeError::TooLarge { size, limit })) => {
+                assert_eq!((size, limit), (1024, 512));
+            }
+            other => panic!("expected the payload to be refused, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! The error type shared by every fallible operation of this crate.
+
+use std::fmt;
+
+use reqwest::StatusCode;
+
+use crate::{ClockError, DecodeError, Violation};
+
+/// Why building, sending or decoding a request failed.
+#[derive(Debug)]
+pub enum RemoteWriteError {
+    /// Metric families could not be converted into a request, because the clock is unusable.
+    Conversion(ClockError),
+    /// The request breaks the remote write specification.
+    Validation(Vec<Violation>),
+    /// The encoded request could not be snappy compressed.
+    Compression(snap::Error),
+    /// The http request could not be built, e.g. because the endpoint is not a valid url.
+    HttpBuild(reqwest::Error),
+    /// The request could not be sent or the response could not be read.
+    Transport(reqwest::Error),
+    /// The receiver answered with a status other than 2xx.
+    Status { status: StatusCode, body: String },
+    /// A payload or response body could not be decoded.
+    Decode(DecodeError),
+    /// The [`Pusher`](crate::Pusher) stopped before it could push.
+    Stopped,
+    /// The [`Pusher`](crate::Pusher) did not finish shutting down before the deadline.
+    DeadlineExceeded,
+}
+
+impl RemoteWriteError {
+    /// The status the receiver answered with, if it answered with an unsuccessful one.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            RemoteWriteError::Status { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RemoteWriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemoteWriteError::Conversion(e) => write!(f, "could not convert metrics: {e}"),
+            RemoteWriteError::Validation(violations) => {
+                write!(f, "invalid request: ")?;
+                for (i, violation) in violations.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{violation}")?;
+                }
+                Ok(())
+            }
+            RemoteWriteError::Compression(e) => write!(f, "could not compress request: {e}"),
+            RemoteWriteError::HttpBuild(e) => write!(f, "could not build http request: {e}"),
+            RemoteWriteError::Transport(e) => write!(f, "could not send request: {e}"),
+            RemoteWriteError::Status { status, body } => {
+                write!(f, "receiver answered with {status}: {body}")
+            }
+            RemoteWriteError::Decode(e) => write!(f, "could not decode payload: {e}"),
+            RemoteWriteError::Stopped => write!(f, "pusher stopped"),
+            RemoteWriteError::DeadlineExceeded => write!(f, "pusher did not shut down in time"),
+        }
+    }
+}
+
+impl std::error::Error for RemoteWriteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RemoteWriteError::Conversion(e) => Some(e),
+            RemoteWriteError::Compression(e) => Some(e),
+            RemoteWriteError::HttpBuild(e) | RemoteWriteError::Transport(e) => Some(e),
+            RemoteWriteError::Validation(_)
+            | RemoteWriteError::Status { .. }
+            | RemoteWriteError::Stopped
+            | RemoteWriteError::DeadlineExceeded => None,
+            RemoteWriteError::Decode(e) => Some(e),
+        }
+    }
+}
+
+impl From<ClockError> for RemoteWriteError {
+    fn from(value: ClockError) -> Self {
+        RemoteWriteError::Conversion(value)
+    }
+}
+
+impl From<snap::Error> for RemoteWriteError {
+    fn from(value: snap::Error) -> Self {
+        RemoteWriteError::Compression(value)
+    }
+}
+
+/// Errors from building a request are reported as [`RemoteWriteError::HttpBuild`],
+/// all others as [`RemoteWriteError::Transport`].
+impl From<reqwest::Error> for RemoteWriteError {
+    fn from(value: reqwest::Error) -> Self {
+        if value.is_builder() {
+            RemoteWriteError::HttpBuild(value)
+        } else {
+            RemoteWriteError::Transport(value)
+        }
+    }
+}
+
+impl From<DecodeError> for RemoteWriteError {
+    fn from(value: DecodeError) -> Self {
+        RemoteWriteError::Decode(value)
+    }
+}
+
+impl From<prost::DecodeError> for RemoteWriteError {
+    fn from(value: prost::DecodeError) -> Self {
+        RemoteWriteError::Decode(value.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConversionOptions, WriteRequest};
+    use pretty_assertions::assert_eq;
+    use reqwest::Client;
+
+    #[derive(Debug)]
+    struct Unsynchronized;
+
+    impl crate::Clock for Unsynchronized {
+        fn now_ms(&self) -> Result<i64, ClockError> {
+            Err(ClockError::new(std::io::Error::other("not synchronized")))
+        }
+    }
+
+    #[test]
+    pub fn reports_failures_instead_of_panicking() {
+        let options = ConversionOptions::new().with_clock(Unsynchronized);
+        let result = WriteRequest::from_metric_families_with_options(&[], &options);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "could not convert metrics: clock failed: not synchronized"
+        );
+
+        let result = WriteRequest::default().build_http_request(Client::new(), "not a url", "test");
+        assert!(matches!(result, Err(RemoteWriteError::HttpBuild(_))));
+    }
+}
@@ -0,0 +1,80 @@
+//! Formatting of floats in label values the way Prometheus client libraries do.
+//!
+//! `le` and `quantile` label values have to match what other services send
+//! byte for byte, or one histogram ends up split over several series. The Go
+//! client formats them with `strconv.FormatFloat(f, 'g', -1, 64)`, special
+//! casing infinities and NaN.
+
+/// Format `value` like Go's `strconv.FormatFloat(value, 'g', -1, 64)`, with `+Inf`, `-Inf` and `NaN`.
+pub(crate) fn format_float(value: f64) -> String {
+    if value.is_nan() {
+        return "NaN".to_string();
+    }
+    if value.is_infinite() {
+        return if value > 0.0 { "+Inf" } else { "-Inf" }.to_string();
+    }
+    // Rust's `{:e}` gives the shortest digits that round trip, e.g. `-1.25e-5`.
+    let scientific = format!("{:e}", value.abs());
+    let (mantissa, exponent) = scientific
+        .split_once('e')
+        .expect("scientific notation has an exponent");
+    let exponent: i32 = exponent.parse().expect("exponent is an integer");
+    let digits = mantissa.replace('.', "");
+    let sign = if value.is_sign_negative() { "-" } else { "" };
+    // For the shortest representation Go uses an exponent when it is below -4 or
+    // at least 6, written with a sign and at least two digits: `1e-05`, `1e+06`.
+    if !(-4..6).contains(&exponent) {
+        let exponent_sign = if exponent < 0 { '-' } else { '+' };
+        return format!("{sign}{mantissa}e{exponent_sign}{:02}", exponent.abs());
+    }
+    let point = exponent + 1;
+    if point <= 0 {
+        format!("{sign}0.{}{digits}", "0".repeat(-point as usize))
+    } else if point as usize >= digits.len() {
+        format!(
+            "{sign}{digits}{}",
+            "0".repeat(point as usize - digits.len())
+        )
+    } else {
+        let (whole, fraction) = digits.split_at(point as usize);
+        format!("{sign}{whole}.{fraction}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    pub fn formats_like_go() {
+        for (value, expected) in [
+            (0.0, "0"),
+            (-0.0, "-0"),
+            (1.0, "1"),
+            (-1.0, "-1"),
+            (0.1, "0.1"),
+            (0.25, "0.25"),
+            (0.005, "0.005"),
+            (0.0001, "0.0001"),
+            (0.00001, "1e-05"),
+            (0.000123, "0.000123"),
+            (2.5, "2.5"),
+            (10.0, "10"),
+            (10000.0, "10000"),
+            (100000.0, "100000"),
+            (123456.0, "123456"),
+            (1000000.0, "1e+06"),
+            (1234567.0, "1.234567e+06"),
+            (2.5e7, "2.5e+07"),
+            (1e21, "1e+21"),
+            (1e100, "1e+100"),
+            (5e-324, "5e-324"),
+            (f64::INFINITY, "+Inf"),
+            (f64::NEG_INFINITY, "-Inf"),
+            (f64::NAN, "NaN"),
+        ] {
+            assert_eq!(format_float(value), expected, "formatting {value:?}");
+        }
+    }
+}
@@ -0,0 +1,403 @@
+//! Native histograms.
+//!
+//! A native histogram is a single sample holding sparse exponential buckets,
+//! rather than one series per classic `le` bucket. Buckets are addressed by an
+//! integer index; with schema `s` bucket `i` covers `(base^(i-1), base^i]` where
+//! `base = 2^(2^-s)`. Populated buckets are sent as spans of consecutive
+//! indexes, and their counts as deltas to the previous bucket.
+
+use std::{collections::BTreeMap, sync::OnceLock};
+
+use crate::{Label, TimeSeries, LABEL_NAME};
+
+/// Schema of native histograms with custom bucket boundaries (NHCB).
+pub const CUSTOM_BUCKETS_SCHEMA: i32 = -53;
+/// Smallest schema of an exponential native histogram.
+pub const MIN_SCHEMA: i32 = -4;
+/// Largest schema of an exponential native histogram.
+pub const MAX_SCHEMA: i32 = 8;
+
+/// A range of consecutive populated buckets.
+///
+/// .proto:
+/// ```protobuf
+/// message BucketSpan {
+///   sint32 offset = 1; // Gap to previous span, or starting point for 1st span (which can be negative).
+///   uint32 length = 2; // Length of consecutive buckets.
+/// }
+/// ```
+#[derive(prost::Message, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct BucketSpan {
+    #[prost(sint32, tag = "1")]
+    pub offset: i32,
+    #[prost(uint32, tag = "2")]
+    pub length: u32,
+}
+
+/// Total number of observations.
+#[derive(prost::Oneof, Clone, Copy, PartialEq)]
+pub enum HistogramCount {
+    #[prost(uint64, tag = "1")]
+    CountInt(u64),
+    #[prost(double, tag = "2")]
+    CountFloat(f64),
+}
+
+/// Number of observations in the zero bucket.
+#[derive(prost::Oneof, Clone, Copy, PartialEq)]
+pub enum ZeroCount {
+    #[prost(uint64, tag = "6")]
+    ZeroCountInt(u64),
+    #[prost(double, tag = "7")]
+    ZeroCountFloat(f64),
+}
+
+/// Whether a histogram is known to have been reset since the previous sample.
+///
+/// .proto:
+/// ```protobuf
+/// enum ResetHint {
+///   UNKNOWN = 0; // Need to test for a counter reset explicitly.
+///   YES     = 1; // This is the 1st histogram after a counter reset.
+///   NO      = 2; // There was no counter reset between this and the previous Histogram.
+///   GAUGE   = 3; // This is a gauge histogram where counter resets don't happen.
+/// }
+/// ```
+#[derive(prost::Enumeration, Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(i32)]
+pub enum ResetHint {
+    Unknown = 0,
+    Yes = 1,
+    No = 2,
+    Gauge = 3,
+}
+
+/// A native histogram sample. Used by both the 1.0 and 2.0 messages.
+///
+/// .proto:
+/// ```protobuf
+/// message Histogram {
+///   oneof count {
+///     uint64 count_int   = 1;
+///     double count_float = 2;
+///   }
+///   double sum = 3;
+///   sint32 schema             = 4;
+///   double zero_threshold     = 5;
+///   oneof zero_count {
+///     uint64 zero_count_int     = 6;
+///     double zero_count_float   = 7;
+///   }
+///   repeated BucketSpan negative_spans  = 8;
+///   repeated sint64     negative_deltas = 9;
+///   repeated double     negative_counts = 10;
+///   repeated BucketSpan positive_spans  = 11;
+///   repeated sint64     positive_deltas = 12;
+///   repeated double     positive_counts = 13;
+///   ResetHint reset_hint = 14;
+///   int64 timestamp = 15;
+///   repeated double custom_values = 16;
+/// }
+/// ```
+#[derive(prost::Message, Clone, PartialEq)]
+pub struct NativeHistogram {
+    #[prost(oneof = "HistogramCount", tags = "1, 2")]
+    pub count: Option<HistogramCount>,
+    #[prost(double, tag = "3")]
+    pub sum: f64,
+    #[prost(sint32, tag = "4")]
+    pub schema: i32,
+    #[prost(double, tag = "5")]
+    pub zero_threshold: f64,
+    #[prost(oneof = "ZeroCount", tags = "6, 7")]
+    pub zero_count: Option<ZeroCount>,
+    #[prost(message, repeated, tag = "8")]
+    pub negative_spans: Vec<BucketSpan>,
+    #[prost(sint64, repeated, tag = "9")]
+    pub negative_deltas: Vec<i64>,
+    #[prost(double, repeated, tag = "10")]
+    pub negative_counts: Vec<f64>,
+    #[prost(message, repeated, tag = "11")]
+    pub positive_spans: Vec<BucketSpan>,
+    #[prost(sint64, repeated, tag = "12")]
+    pub positive_deltas: Vec<i64>,
+    #[prost(double, repeated, tag = "13")]
+    pub positive_counts: Vec<f64>,
+    #[prost(enumeration = "ResetHint", tag = "14")]
+    pub reset_hint: i32,
+    #[prost(int64, tag = "15")]
+    pub timestamp: i64,
+    /// Upper bounds of the buckets of a custom buckets histogram, without `+Inf`.
+    #[prost(double, repeated, tag = "16")]
+    pub custom_values: Vec<f64>,
+}
+
+/// Encode bucket counts keyed by index as spans and deltas.
+fn spans_and_deltas(buckets: &BTreeMap<i32, u64>) -> (Vec<BucketSpan>, Vec<i64>) {
+    let mut spans: Vec<BucketSpan> = Vec::new();
+    let mut deltas = Vec::with_capacity(buckets.len());
+    let mut next_index = None;
+    let mut previous_count = 0;
+    for (&index, &count) in buckets {
+        match (spans.last_mut(), next_index) {
+            (Some(span), Some(next)) if next == index => span.length += 1,
+            (_, next) => spans.push(BucketSpan {
+                offset: next.map(|next| index - next).unwrap_or(index),
+                length: 1,
+            }),
+        }
+        deltas.push(count as i64 - previous_count as i64);
+        previous_count = count;
+        next_index = Some(index + 1);
+    }
+    (spans, deltas)
+}
+
+impl NativeHistogram {
+    /// Encode a classic histogram as a native histogram with custom buckets.
+    ///
+    /// The classic bucket upper bounds become the custom values, and the last
+    /// bucket is the implicit `+Inf` one.
+    pub fn from_classic(histogram: &prometheus::proto::Histogram, timestamp: i64) -> Self {
+        let mut custom_values = Vec::new();
+        let mut buckets = BTreeMap::new();
+        let mut previous = 0;
+        for bucket in histogram.get_bucket() {
+            if bucket.upper_bound().is_infinite() {
+                break;
+            }
+            let count = bucket.cumulative_count().saturating_sub(previous);
+            if count > 0 {
+                buckets.insert(custom_values.len() as i32, count);
+            }
+            custom_values.push(bucket.upper_bound());
+            previous = bucket.cumulative_count();
+        }
+        let overflow = histogram.get_sample_count().saturating_sub(previous);
+        if overflow > 0 {
+            buckets.insert(custom_values.len() as i32, overflow);
+        }
+        let (positive_spans, positive_deltas) = spans_and_deltas(&buckets);
+        Self {
+            count: Some(HistogramCount::CountInt(histogram.get_sample_count())),
+            sum: histogram.get_sample_sum(),
+            schema: CUSTOM_BUCKETS_SCHEMA,
+            positive_spans,
+            positive_deltas,
+            timestamp,
+            custom_values,
+            ..Default::default()
+        }
+    }
+}
+
+/// `v` as a fraction in `[0.5, 1)` and a power of two, like C's `frexp`.
+fn frexp(v: f64) -> (f64, i32) {
+    if v == 0.0 || !v.is_finite() {
+        return (v, 0);
+    }
+    let (v, adjust) = if v.abs() < f64::MIN_POSITIVE {
+        (v * 2f64.powi(54), -54)
+    } else {
+        (v, 0)
+    };
+    let bits = v.to_bits();
+    let exp = ((bits >> 52) & 0x7ff) as i32 - 1022;
+    let frac = f64::from_bits((bits & !(0x7ff << 52)) | (1022 << 52));
+    (frac, exp + adjust)
+}
+
+/// Upper bounds of the buckets within a power of two for a positive `schema`, as
+/// fractions of that power, i.e. `2^(i/2^schema - 1)`. Computed once per schema.
+fn bounds(schema: i32) -> &'static [f64] {
+    static BOUNDS: [OnceLock<Vec<f64>>; MAX_SCHEMA as usize] =
+        [const { OnceLock::new() }; MAX_SCHEMA as usize];
+    BOUNDS[schema as usize - 1].get_or_init(|| {
+        let per_power = 1 << schema;
+        (0..per_power)
+            .map(|i| 2f64.powf(i as f64 / per_power as f64 - 1.0))
+            .collect()
+    })
+}
+
+/// Index of the bucket that `v` (positive) falls into with the given schema.
+fn bucket_index(v: f64, schema: i32) -> i32 {
+    // Like client_golang, +Inf goes into the bucket right after the one of the largest float.
+    if v.is_infinite() {
+        return bucket_index(f64::MAX, schema) + 1;
+    }
+    let (frac, exp) = frexp(v);
+    if schema > 0 {
+        let in_power = bounds(schema).partition_point(|bound| *bound < frac) as i32;
+        in_power + (exp - 1) * (1 << schema)
+    } else {
+        let mut index = exp;
+        if frac == 0.5 {
+            index -= 1;
+        }
+        let offset = (1 << -schema) - 1;
+        (index + offset) >> -schema
+    }
+}
+
+/// Accumulates observations into an exponential native histogram.
+#[derive(Debug, Clone)]
+pub struct NativeHistogramBuilder {
+    schema: i32,
+    zero_threshold: f64,
+    count: u64,
+    sum: f64,
+    zero_count: u64,
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+}
+
+impl NativeHistogramBuilder {
+    /// A builder with the given schema, clamped to `MIN_SCHEMA..=MAX_SCHEMA`.
+    ///
+    /// Higher schemas give finer buckets; schema 3, the Prometheus default, has
+    /// 8 buckets per power of two.
+    pub fn new(schema: i32) -> Self {
+        Self {
+            schema: schema.clamp(MIN_SCHEMA, MAX_SCHEMA),
+            zero_threshold: f64::MIN_POSITIVE,
+            count: 0,
+            sum: 0.0,
+            zero_count: 0,
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+        }
+    }
+
+    /// Observations with an absolute value up to `zero_threshold` go into the zero bucket.
+    pub fn with_zero_threshold(mut self, zero_threshold: f64) -> Self {
+        self.zero_threshold = zero_threshold.abs();
+        self
+    }
+
+    /// Record a single observation. NaN observations are ignored.
+    pub fn observe(&mut self, v: f64) {
+        if v.is_nan() {
+            return;
+        }
+        self.count += 1;
+        self.sum += v;
+        if v.abs() <= self.zero_threshold {
+            self.zero_count += 1;
+        } else if v > 0.0 {
+            *self
+                .positive
+                .entry(bucket_index(v, self.schema))
+                .or_default() += 1;
+        } else {
+            *self
+                .negative
+                .entry(bucket_index(-v, self.schema))
+                .or_default() += 1;
+        }
+    }
+
+    /// The histogram of everything observed so far.
+    pub fn build(&self, timestamp: i64) -> NativeHistogram {
+        let (positive_spans, positive_deltas) = spans_and_deltas(&self.positive);
+        let (negative_spans, negative_deltas) = spans_and_deltas(&self.negative);
+        NativeHistogram {
+            count: Some(HistogramCount::CountInt(self.count)),
+            sum: self.sum,
+            schema: self.schema,
+            zero_threshold: self.zero_threshold,
+            zero_count: Some(ZeroCount::ZeroCountInt(self.zero_count)),
+            negative_spans,
+            negative_deltas,
+            positive_spans,
+            positive_deltas,
+            timestamp,
+            ..Default::default()
+        }
+    }
+}
+
+impl TimeSeries {
+    /// A series called `name` with `labels`, holding a single native histogram sample.
+    pub fn from_native_histogram(
+        name: &str,
+        labels: &[(&str, &str)],
+        histogram: NativeHistogram,
+    ) -> Self {
+        let mut series_labels = vec![Label {
+            name: LABEL_NAME.to_string(),
+            value: name.to_string(),
+        }];
+        series_labels.extend(labels.iter().map(|(k, v)| Label {
+            name: k.to_string(),
+            value: v.to_string(),
+        }));
+        let mut series = TimeSeries {
+            labels: series_labels,
+            histograms: vec![histogram],
+            ..Default::default()
+        };
+        series.sort_labels_and_samples();
+        series
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    pub fn computes_bucket_indexes() {
+        assert_eq!(bucket_index(1.0, 0), 0);
+        assert_eq!(bucket_index(1.5, 0), 1);
+        assert_eq!(bucket_index(2.0, 0), 1);
+        assert_eq!(bucket_index(3.0, 0), 2);
+        assert_eq!(bucket_index(1.0, 1), 0);
+        assert_eq!(bucket_index(1.2, 1), 1);
+        assert_eq!(bucket_index(3.0, -1), 1);
+        assert_eq!(bucket_index(0.25, 0), -2);
+        assert_eq!(bucket_index(f64::MAX, 0), 1024);
+        assert_eq!(bucket_index(f64::INFINITY, 0), 1025);
+        assert_eq!(bucket_index(f64::MAX, 3), 8192);
+        assert_eq!(bucket_index(f64::INFINITY, 3), 8193);
+        assert_eq!(bucket_index(f64::INFINITY, -1), 513);
+    }
+
+    #[test]
+    pub fn encodes_spans_and_deltas() {
+        let mut builder = NativeHistogramBuilder::new(0);
+        for v in [1.0, 1.5, 3.0, 3.5, 100.0, 0.0, -1.0] {
+            builder.observe(v);
+        }
+        let histogram = builder.build(42);
+        assert_eq!(histogram.count, Some(HistogramCount::CountInt(7)));
+        assert_eq!(histogram.sum, 108.0);
+        assert_eq!(histogram.zero_count, Some(ZeroCount::ZeroCountInt(1)));
+        assert_eq!(
+            histogram.positive_spans,
+            vec![
+                BucketSpan {
+                    offset: 0,
+                    length: 3
+                },
+                BucketSpan {
+                    offset: 4,
+                    length: 1
+                }
+            ]
+        );
+        assert_eq!(histogram.positive_deltas, vec![1, 0, 1, -1]);
+        assert_eq!(
+            histogram.negative_spans,
+            vec![BucketSpan {
+                offset: 0,
+                length: 1
+            }]
+        );
+        assert_eq!(histogram.negative_deltas, vec![1]);
+        let decoded: NativeHistogram =
+            prost::Message::decode(prost::Message::encode_to_vec(&histogram).as_slice()).unwrap();
+        assert_eq!(decoded, histogram);
+    }
+}
@@ -1,16 +1,69 @@
-use std::{collections::HashMap, time::SystemTime};
-
 use prometheus::proto::MetricFamily;
 use reqwest::Client;
 
+use crate::{convert::for_each_series, decode::decompress};
+
+mod chunkenc;
+mod client;
+mod clock;
+mod convert;
+mod decode;
+mod error;
+mod float;
+mod histogram;
+mod metadata;
+mod negotiation;
+mod options;
+mod pusher;
+pub mod read;
+#[cfg(feature = "receiver")]
+mod receiver;
+mod response;
+mod retry;
+mod staleness;
+pub mod v2;
+mod validation;
+
+pub use client::{RemoteWriteClient, WriteOutcome};
+pub use clock::{Clock, ClockError, FixedClock, SystemClock};
+pub use decode::{DecodeError, DEFAULT_MAX_DECOMPRESSED_SIZE};
+pub use error::RemoteWriteError;
+pub use histogram::{
+    BucketSpan, HistogramCount, NativeHistogram, NativeHistogramBuilder, ResetHint, ZeroCount,
+    CUSTOM_BUCKETS_SCHEMA, MAX_SCHEMA, MIN_SCHEMA,
+};
+pub use metadata::{MetadataThrottle, MetadataType, MetricMetadata};
+pub use negotiation::{ProtocolNegotiator, ProtocolVersion};
+pub use options::{ConversionOptions, HistogramEncoding, LabelCollision};
+pub use pusher::{PushCallback, Pusher, PusherHandle};
+#[cfg(feature = "receiver")]
+pub use receiver::{Receiver, ReceiverResponse, SinkError, WriteSink};
+pub use response::{WriteResponse, WriteStats};
+pub use retry::RetryPolicy;
+pub use staleness::StalenessTracker;
+pub use validation::Violation;
+
 /// Special label for the name of a metric.
 pub const LABEL_NAME: &str = "__name__";
 pub const CONTENT_TYPE: &str = "application/x-protobuf";
 pub const HEADER_NAME_REMOTE_WRITE_VERSION: &str = "X-Prometheus-Remote-Write-Version";
 pub const REMOTE_WRITE_VERSION_01: &str = "0.1.0";
+/// Content type of a Remote Write 2.0 (`io.prometheus.write.v2.Request`) body.
+pub const CONTENT_TYPE_V2: &str = "application/x-protobuf;proto=io.prometheus.write.v2.Request";
+pub const REMOTE_WRITE_VERSION_20: &str = "2.0.0";
+pub const HEADER_NAME_SAMPLES_WRITTEN: &str = "X-Prometheus-Remote-Write-Samples-Written";
+pub const HEADER_NAME_HISTOGRAMS_WRITTEN: &str = "X-Prometheus-Remote-Write-Histograms-Written";
+pub const HEADER_NAME_EXEMPLARS_WRITTEN: &str = "X-Prometheus-Remote-Write-Exemplars-Written";
 pub const COUNT_SUFFIX: &str = "_count";
 pub const SUM_SUFFIX: &str = "_sum";
 pub const TOTAL_SUFFIX: &str = "_total";
+pub const CREATED_SUFFIX: &str = "_created";
+/// Bits of [`STALE_NAN`].
+pub const STALE_NAN_BITS: u64 = 0x7ff0000000000002;
+/// The NaN Prometheus uses as a sample value to mark a series as stale, so it ends
+/// right away instead of staying visible for the 5 minute lookback. Compare bits,
+/// not values, since every NaN compares unequal; see [`Sample::is_stale`].
+pub const STALE_NAN: f64 = f64::from_bits(STALE_NAN_BITS);
 
 /// A label.
 ///
@@ -46,6 +99,54 @@ pub struct Sample {
     pub timestamp: i64,
 }
 
+impl Sample {
+    /// True if this sample is a staleness marker, i.e. its value is [`STALE_NAN`].
+    pub fn is_stale(&self) -> bool {
+        self.value.to_bits() == STALE_NAN_BITS
+    }
+}
+
+/// An exemplar: a sample with labels pointing at something outside the metric, such as a trace.
+///
+/// .proto:
+/// ```protobuf
+/// message Exemplar {
+///   repeated Label labels = 1;
+///   double value          = 2;
+///   int64 timestamp       = 3;
+/// }
+/// ```
+#[derive(prost::Message, Clone, PartialEq)]
+pub struct Exemplar {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(double, tag = "2")]
+    pub value: f64,
+    #[prost(int64, tag = "3")]
+    pub timestamp: i64,
+}
+
+impl Exemplar {
+    pub fn new(labels: &[(&str, &str)], value: f64, timestamp: i64) -> Self {
+        Self {
+            labels: labels
+                .iter()
+                .map(|(k, v)| Label {
+                    name: k.to_string(),
+                    value: v.to_string(),
+                })
+                .collect(),
+            value,
+            timestamp,
+        }
+    }
+
+    /// An exemplar linking to a trace through the conventional `trace_id` label.
+    pub fn with_trace_id(trace_id: &str, value: f64, timestamp: i64) -> Self {
+        Self::new(&[("trace_id", trace_id)], value, timestamp)
+    }
+}
+
 pub enum ExtraLabel {
     LessThan(f64),
     Quantile(f64),
@@ -56,8 +157,10 @@ pub enum ExtraLabel {
 /// .proto:
 /// ```protobuf
 /// message TimeSeries {
-///   repeated Label labels   = 1;
-///   repeated Sample samples = 2;
+///   repeated Label labels       = 1;
+///   repeated Sample samples     = 2;
+///   repeated Exemplar exemplars = 3;
+///   repeated Histogram histograms = 4;
 /// }
 /// ```
 #[derive(prost::Message, Clone, PartialEq)]
@@ -66,6 +169,10 @@ pub struct TimeSeries {
     pub labels: Vec<Label>,
     #[prost(message, repeated, tag = "2")]
     pub samples: Vec<Sample>,
+    #[prost(message, repeated, tag = "3")]
+    pub exemplars: Vec<Exemplar>,
+    #[prost(message, repeated, tag = "4")]
+    pub histograms: Vec<NativeHistogram>,
 }
 
 impl TimeSeries {
@@ -74,7 +181,23 @@ impl TimeSeries {
     /// Required by the specification.
     pub fn sort_labels_and_samples(&mut self) {
         self.labels.sort_by(|a, b| a.name.cmp(&b.name));
-        self.samples.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        self.samples.sort_by_key(|s| s.timestamp);
+        self.exemplars.sort_by_key(|e| e.timestamp);
+        self.histograms.sort_by_key(|h| h.timestamp);
+    }
+
+    /// The value of the label called `name`, if this series has it.
+    pub fn label(&self, name: &str) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|l| l.name == name)
+            .map(|l| l.value.as_str())
+    }
+
+    /// True if this series is called `name` and has every label in `labels`.
+    fn matches(&self, name: &str, labels: &[(&str, &str)]) -> bool {
+        self.label(LABEL_NAME) == Some(name)
+            && labels.iter().all(|(k, v)| self.label(k) == Some(*v))
     }
 }
 
@@ -87,23 +210,18 @@ impl TimeSeries {
 ///   // Cortex uses this field to determine the source of the write request.
 ///   // We reserve it to avoid any compatibility issues.
 ///   reserved  2;
-
-///   // Prometheus uses this field to send metadata, but this is
-///   // omitted from v1 of the spec as it is experimental.
-///   reserved  3;
+///
+///   // Omitted from v1 of the spec as it is experimental, but Prometheus
+///   // sends it and receivers such as Mimir store it.
+///   repeated MetricMetadata metadata = 3;
 /// }
 /// ```
 #[derive(prost::Message, Clone, PartialEq)]
 pub struct WriteRequest {
     #[prost(message, repeated, tag = "1")]
     pub timeseries: Vec<TimeSeries>,
-}
-
-fn get_timestamp() -> i64 {
-    SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as i64
+    #[prost(message, repeated, tag = "3")]
+    pub metadata: Vec<MetricMetadata>,
 }
 
 impl WriteRequest {
@@ -122,6 +240,90 @@ impl WriteRequest {
         self
     }
 
+    /// Count what this request contains, to compare against what a receiver reports as written.
+    pub fn stats(&self) -> WriteStats {
+        WriteStats::count(
+            self.timeseries
+                .iter()
+                .map(|ts| (ts.samples.len(), ts.histograms.len(), ts.exemplars.len())),
+        )
+    }
+
+    /// A request marking each series, given by its labels, as stale at `timestamp`.
+    pub fn stale_markers(series: impl IntoIterator<Item = Vec<Label>>, timestamp: i64) -> Self {
+        Self {
+            timeseries: series
+                .into_iter()
+                .map(|labels| TimeSeries {
+                    labels,
+                    samples: vec![Sample {
+                        value: STALE_NAN,
+                        timestamp,
+                    }],
+                    ..Default::default()
+                })
+                .collect(),
+            metadata: vec![],
+        }
+        .sorted()
+    }
+
+    /// Attach `exemplar` to the counter or gauge series called `name` that has all of `labels`.
+    ///
+    /// Returns false if there is no such series.
+    pub fn add_exemplar(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        exemplar: Exemplar,
+    ) -> bool {
+        match self
+            .timeseries
+            .iter_mut()
+            .find(|ts| ts.matches(name, labels))
+        {
+            Some(series) => {
+                series.exemplars.push(exemplar);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Attach `exemplar` to the bucket of the histogram `name` with all of `labels`
+    /// that the exemplar value falls into, i.e. the one with the lowest `le` at or above it.
+    ///
+    /// Returns false if the histogram has no such bucket.
+    pub fn add_histogram_exemplar(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        exemplar: Exemplar,
+    ) -> bool {
+        let bucket = self
+            .timeseries
+            .iter_mut()
+            .filter(|ts| ts.matches(name, labels))
+            .filter_map(|ts| {
+                let le = ts.label("le")?;
+                let le = if le == "+Inf" {
+                    f64::INFINITY
+                } else {
+                    le.parse::<f64>().ok()?
+                };
+                Some((le, ts))
+            })
+            .filter(|(le, _)| exemplar.value <= *le)
+            .min_by(|(a, _), (b, _)| a.total_cmp(b));
+        match bucket {
+            Some((_, series)) => {
+                series.exemplars.push(exemplar);
+                true
+            }
+            None => false,
+        }
+    }
+
     /// Encode this write request as a protobuf message.
     ///
     /// NOTE: The API requires snappy compression, not a raw protobuf message.
@@ -129,261 +331,138 @@ impl WriteRequest {
         prost::Message::encode_to_vec(&self.sorted())
     }
 
-    pub fn encode_compressed(self) -> Result<Vec<u8>, snap::Error> {
-        snap::raw::Encoder::new().compress_vec(&self.encode_proto3())
+    pub fn encode_compressed(self) -> Result<Vec<u8>, RemoteWriteError> {
+        Ok(snap::raw::Encoder::new().compress_vec(&self.encode_proto3())?)
+    }
+
+    /// Decode a raw protobuf message.
+    pub fn decode_proto3(buf: &[u8]) -> Result<Self, RemoteWriteError> {
+        Ok(prost::Message::decode(buf)?)
+    }
+
+    /// Decode a snappy compressed payload, as sent over the wire.
+    ///
+    /// Payloads that decompress to more than [`DEFAULT_MAX_DECOMPRESSED_SIZE`] are refused.
+    pub fn decode_compressed(buf: &[u8]) -> Result<Self, RemoteWriteError> {
+        Self::decode_compressed_with_limit(buf, DEFAULT_MAX_DECOMPRESSED_SIZE)
+    }
+
+    /// Decode a snappy compressed payload that decompresses to at most `max_size` bytes.
+    pub fn decode_compressed_with_limit(
+        buf: &[u8],
+        max_size: usize,
+    ) -> Result<Self, RemoteWriteError> {
+        Self::decode_proto3(&decompress(buf, max_size)?)
     }
 
     /// Encode Prometheus metric families into a WriteRequest
     pub fn from_metric_families(
         metric_families: Vec<MetricFamily>,
         custom_labels: Option<Vec<(String, String)>>,
-    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+    ) -> Result<Self, RemoteWriteError> {
+        let options =
+            ConversionOptions::new().with_custom_labels(custom_labels.unwrap_or_default());
+        Self::from_metric_families_with_options(&metric_families, &options)
+    }
+
+    /// Encode Prometheus metric families into a WriteRequest, as configured by `options`.
+    pub fn from_metric_families_with_options(
+        metric_families: &[MetricFamily],
+        options: &ConversionOptions,
+    ) -> Result<Self, RemoteWriteError> {
+        let now = options.clock.now_ms()?;
         let mut timeseries = Vec::new();
-        let now = get_timestamp();
-        let custom_labels = custom_labels.unwrap_or_default();
-        metric_families
-            .iter()
-            .for_each(|mf| match mf.get_field_type() {
-                prometheus::proto::MetricType::GAUGE => {
-                    mf.get_metric().iter().for_each(|m| {
-                        let mut labels = m
-                            .get_label()
-                            .iter()
-                            .map(|l| (l.name().to_string(), l.value().to_string()))
-                            .collect::<Vec<_>>();
-                        labels.push((LABEL_NAME.to_string(), mf.name().to_string()));
-                        labels.extend_from_slice(&custom_labels);
-
-                        let samples = vec![Sample {
-                            value: m.get_gauge().value(),
-                            timestamp: now,
-                        }];
-
-                        timeseries.push(TimeSeries {
-                            labels: labels
-                                .iter()
-                                .map(|(k, v)| Label {
-                                    name: k.to_string(),
-                                    value: v.to_string(),
-                                })
-                                .collect::<Vec<_>>(),
-                            samples,
-                        });
-                    });
-                }
-                prometheus::proto::MetricType::COUNTER => {
-                    mf.get_metric().iter().for_each(|m| {
-                        let mut labels = m
-                            .get_label()
-                            .iter()
-                            .map(|l| (l.name().to_string(), l.value().to_string()))
-                            .collect::<Vec<_>>();
-                        labels.push((LABEL_NAME.to_string(), mf.name().to_string()));
-                        labels.extend_from_slice(&custom_labels);
-                        let samples = vec![Sample {
-                            value: m.get_counter().value(),
-                            timestamp: now,
-                        }];
-
-                        timeseries.push(TimeSeries {
-                            labels: labels
-                                .iter()
-                                .map(|(k, v)| Label {
-                                    name: k.to_string(),
-                                    value: v.to_string(),
-                                })
-                                .collect::<Vec<_>>(),
-                            samples,
-                        });
-                    });
-                }
-                prometheus::proto::MetricType::SUMMARY => {
-                    mf.get_metric().iter().for_each(|m| {
-                        let mut labels = m
-                            .get_label()
-                            .iter()
-                            .map(|l| (l.name().to_string(), l.value().to_string()))
-                            .collect::<HashMap<String, String>>();
-                        labels.insert(LABEL_NAME.to_string(), mf.name().to_string());
-                        custom_labels.iter().for_each(|(k, v)| {
-                            labels.insert(k.to_string(), v.to_string());
-                        });
-                        m.get_summary().get_quantile().iter().for_each(|quantile| {
-                            let mut our_labels = labels.clone();
-                            our_labels.insert(
-                                "quantile".to_string(),
-                                quantile.quantile().to_string(),
-                            );
-                            let samples = vec![Sample {
-                                value: quantile.value(),
-                                timestamp: now,
-                            }];
-                            timeseries.push(TimeSeries {
-                                labels: our_labels
-                                    .iter()
-                                    .map(|(k, v)| Label {
-                                        name: k.to_string(),
-                                        value: v.to_string(),
-                                    })
-                                    .collect::<Vec<_>>(),
-                                samples,
-                            });
-                        });
-                        let mut top_level_labels = labels.clone();
-                        top_level_labels.insert(
-                            LABEL_NAME.to_string(),
-                            format!("{}{}", mf.name(), SUM_SUFFIX),
-                        );
-                        timeseries.push(TimeSeries {
-                            samples: vec![Sample {
-                                value: m.get_summary().sample_sum(),
-                                timestamp: now,
-                            }],
-                            labels: top_level_labels
-                                .iter()
-                                .map(|(k, v)| Label {
-                                    name: k.to_string(),
-                                    value: v.to_string(),
-                                })
-                                .collect(),
-                        });
-                        top_level_labels.insert(
-                            LABEL_NAME.to_string(),
-                            format!("{}{}", mf.name(), COUNT_SUFFIX),
-                        );
-                        timeseries.push(TimeSeries {
-                            samples: vec![Sample {
-                                value: m.get_summary().sample_count() as f64,
-                                timestamp: now,
-                            }],
-                            labels: top_level_labels
-                                .iter()
-                                .map(|(k, v)| Label {
-                                    name: k.to_string(),
-                                    value: v.to_string(),
-                                })
-                                .collect(),
-                        });
-                    });
-                }
-                prometheus::proto::MetricType::UNTYPED => {}
-                prometheus::proto::MetricType::HISTOGRAM => {
-                    mf.get_metric().iter().for_each(|m| {
-                        let mut labels = m
-                            .get_label()
-                            .iter()
-                            .map(|l| (l.name().to_string(), l.value().to_string()))
-                            .collect::<HashMap<String, String>>();
-                        labels.insert(LABEL_NAME.to_string(), mf.name().to_string());
-                        custom_labels.iter().for_each(|(k, v)| {
-                            labels.insert(k.to_string(), v.to_string());
-                        });
-                        m.get_histogram().get_bucket().iter().for_each(|bucket| {
-                            let mut our_labels = labels.clone();
-                            our_labels
-                                .insert("le".to_string(), bucket.upper_bound().to_string());
-                            let samples = vec![Sample {
-                                value: bucket.cumulative_count() as f64,
-                                timestamp: now,
-                            }];
-                            timeseries.push(TimeSeries {
-                                labels: our_labels
-                                    .iter()
-                                    .map(|(k, v)| Label {
-                                        name: k.to_string(),
-                                        value: v.to_string(),
-                                    })
-                                    .collect::<Vec<_>>(),
-                                samples,
-                            });
-                        });
-                        let mut top_level_labels = labels.clone();
-                        top_level_labels.insert(
-                            LABEL_NAME.to_string(),
-                            format!("{}{}", mf.name(), SUM_SUFFIX),
-                        );
-                        timeseries.push(TimeSeries {
-                            samples: vec![Sample {
-                                value: m.get_histogram().get_sample_sum(),
-                                timestamp: now,
-                            }],
-                            labels: top_level_labels
-                                .iter()
-                                .map(|(k, v)| Label {
-                                    name: k.to_string(),
-                                    value: v.to_string(),
-                                })
-                                .collect(),
-                        });
-                        top_level_labels.insert(
-                            LABEL_NAME.to_string(),
-                            format!("{}{}", mf.name(), COUNT_SUFFIX),
-                        );
-                        timeseries.push(TimeSeries {
-                            samples: vec![Sample {
-                                value: m.get_histogram().get_sample_count() as f64,
-                                timestamp: now,
-                            }],
-                            labels: top_level_labels
-                                .iter()
-                                .map(|(k, v)| Label {
-                                    name: k.to_string(),
-                                    value: v.to_string(),
-                                })
-                                .collect(),
-                        });
-                        top_level_labels.insert(LABEL_NAME.to_string(), mf.name().to_string());
-                        top_level_labels.insert("le".into(), "+Inf".into());
-                        timeseries.push(TimeSeries {
-                            samples: vec![Sample {
-                                value: m.get_histogram().get_sample_count() as f64,
-                                timestamp: now,
-                            }],
-                            labels: top_level_labels
-                                .iter()
-                                .map(|(k, v)| Label {
-                                    name: k.to_string(),
-                                    value: v.to_string(),
-                                })
-                                .collect(),
-                        });
-                    });
-                }
+        for mf in metric_families {
+            for_each_series(mf, options, now, |series| {
+                timeseries.push(series.into_timeseries())
             });
-        timeseries.sort_by(|a, b| {
-            let name_a = a.labels.iter().find(|l| l.name == LABEL_NAME).unwrap();
-            let name_b = b.labels.iter().find(|l| l.name == LABEL_NAME).unwrap();
-            name_a.value.cmp(&name_b.value)
-        });
-        let s = Self { timeseries };
+        }
+        timeseries.sort_by(|a, b| a.label(LABEL_NAME).cmp(&b.label(LABEL_NAME)));
+        let metadata = metric_families
+            .iter()
+            .map(MetricMetadata::from_metric_family)
+            .collect();
+        let s = Self {
+            timeseries,
+            metadata,
+        };
         Ok(s.sorted())
     }
 
+    /// Encode Prometheus metric families straight into a protobuf `WriteRequest`, as configured by `options`.
+    ///
+    /// Produces the same series and metadata as [`WriteRequest::from_metric_families_with_options`]
+    /// followed by [`WriteRequest::encode_proto3`], without building the request in between.
+    /// Series are in the order of the metric families rather than sorted by name, which
+    /// the specification does not require.
+    pub fn encode_metric_families(
+        metric_families: &[MetricFamily],
+        options: &ConversionOptions,
+    ) -> Result<Vec<u8>, RemoteWriteError> {
+        let now = options.clock.now_ms()?;
+        let mut buf = Vec::new();
+        for mf in metric_families {
+            for_each_series(mf, options, now, |series| series.encode(&mut buf));
+        }
+        for mf in metric_families {
+            prost::encoding::message::encode(3, &MetricMetadata::from_metric_family(mf), &mut buf);
+        }
+        Ok(buf)
+    }
+
+    /// [`WriteRequest::encode_metric_families`], snappy compressed as sent over the wire.
+    pub fn encode_metric_families_compressed(
+        metric_families: &[MetricFamily],
+        options: &ConversionOptions,
+    ) -> Result<Vec<u8>, RemoteWriteError> {
+        let buf = Self::encode_metric_families(metric_families, options)?;
+        Ok(snap::raw::Encoder::new().compress_vec(&buf)?)
+    }
+
     pub fn build_http_request(
         self,
         client: Client,
         endpoint: &str,
         user_agent: &str,
-    ) -> Result<reqwest::Request, reqwest::Error> {
+    ) -> Result<reqwest::Request, RemoteWriteError> {
+        let body = self.encode_compressed()?;
         client
             .post(endpoint)
             .header(reqwest::header::CONTENT_TYPE, CONTENT_TYPE)
             .header(HEADER_NAME_REMOTE_WRITE_VERSION, REMOTE_WRITE_VERSION_01)
             .header(reqwest::header::CONTENT_ENCODING, "snappy")
             .header(reqwest::header::USER_AGENT, user_agent)
-            .body(
-                self.encode_compressed()
-                    .expect("Failed to compress metrics data"),
-            )
+            .body(body)
             .build()
+            .map_err(RemoteWriteError::HttpBuild)
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use pretty_assertions::assert_eq;
-    use prometheus::{histogram_opts, Counter, Gauge, Histogram, Registry};
+    use prometheus::{
+        histogram_opts, Counter, CounterVec, Gauge, Histogram, HistogramVec, Opts, Registry,
+    };
+
+    /// A request with a single `up` sample, for tests that don't care about its contents.
+    pub(crate) fn write_request() -> WriteRequest {
+        WriteRequest {
+            timeseries: vec![TimeSeries {
+                labels: vec![Label {
+                    name: LABEL_NAME.to_string(),
+                    value: "up".to_string(),
+                }],
+                samples: vec![Sample {
+                    value: 1.0,
+                    timestamp: 1,
+                }],
+                ..Default::default()
+            }],
+            metadata: vec![],
+        }
+    }
 
     #[test]
     pub fn can_encode_counter() {
@@ -436,6 +515,46 @@ mod tests {
         assert_eq!(entry.samples.first().unwrap().value, incremented_by);
     }
     #[test]
+    pub fn can_encode_untyped() {
+        let mut untyped = prometheus::proto::Untyped::default();
+        untyped.set_value(3.0);
+        let mut label = prometheus::proto::LabelPair::default();
+        label.set_name("source".to_string());
+        label.set_value("bridge".to_string());
+        let mut metric = prometheus::proto::Metric::default();
+        metric.set_label(vec![label]);
+        metric.untyped = Some(untyped).into();
+        let mut mf = MetricFamily::default();
+        mf.set_name("my_untyped".to_string());
+        mf.set_help("an extra description".to_string());
+        mf.set_field_type(prometheus::proto::MetricType::UNTYPED);
+        mf.set_metric(vec![metric]);
+        let req =
+            WriteRequest::from_metric_families(vec![mf], None).expect("Failed to encode untyped");
+        assert_eq!(req.timeseries.len(), 1);
+        let entry = req.timeseries.first().unwrap();
+        assert_eq!(entry.label(LABEL_NAME), Some("my_untyped"));
+        assert_eq!(entry.label("source"), Some("bridge"));
+        assert_eq!(entry.samples.first().unwrap().value, 3.0);
+        assert_eq!(req.metadata.len(), 1);
+        assert_eq!(req.metadata[0].r#type, MetadataType::Unknown as i32);
+    }
+    #[test]
+    pub fn honors_metric_timestamps() {
+        let registry = Registry::new();
+        let gauge = Gauge::new("my_gauge", "an extra description").unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        let mut mfs = registry.gather();
+        mfs[0].mut_metric()[0].set_timestamp_ms(1234);
+
+        let req = WriteRequest::from_metric_families(mfs.clone(), None).unwrap();
+        assert_eq!(req.timeseries[0].samples[0].timestamp, 1234);
+
+        let options = ConversionOptions::new().with_honor_timestamps(false);
+        let req = WriteRequest::from_metric_families_with_options(&mfs, &options).unwrap();
+        assert_ne!(req.timeseries[0].samples[0].timestamp, 1234);
+    }
+    #[test]
     pub fn can_encode_histogram() {
         let registry = Registry::new();
         let histogram_name = "my_histogram";
@@ -521,4 +640,253 @@ mod tests {
         );
         assert_eq!(entry.samples.first().unwrap().value, incremented_by);
     }
+
+    #[test]
+    pub fn resolves_label_collisions_uniformly() {
+        let registry = Registry::new();
+        let counter = CounterVec::new(Opts::new("my_counter", "help"), &["job"]).unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.with_label_values(&["metric"]).inc();
+        let opts = histogram_opts!("my_histogram", "help", vec![1.0]);
+        let histogram = HistogramVec::new(opts, &["job"]).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        histogram.with_label_values(&["metric"]).observe(0.5);
+        let custom_labels = vec![
+            ("job".to_string(), "custom".to_string()),
+            (LABEL_NAME.to_string(), "renamed".to_string()),
+        ];
+
+        for (policy, job, exported_job) in [
+            (LabelCollision::KeepMetric, "metric", None),
+            (LabelCollision::Override, "custom", None),
+            (LabelCollision::Rename, "custom", Some("metric")),
+        ] {
+            let options = ConversionOptions::new()
+                .with_custom_labels(custom_labels.clone())
+                .with_label_collision(policy);
+            let req = WriteRequest::from_metric_families_with_options(&registry.gather(), &options)
+                .unwrap();
+            assert_eq!(req.validate(), vec![]);
+            for series in &req.timeseries {
+                assert_eq!(series.label("job"), Some(job));
+                assert_eq!(series.label("exported_job"), exported_job);
+                assert!(series.label(LABEL_NAME).unwrap().starts_with("my_"));
+                assert_eq!(series.label("exported___name__"), None);
+            }
+        }
+    }
+
+    #[test]
+    pub fn formats_le_like_go_without_duplicating_inf() {
+        let registry = Registry::new();
+        let opts = histogram_opts!("my_histogram", "help", vec![0.00001, 0.5, 1e6]);
+        let histogram = Histogram::with_opts(opts).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        histogram.observe(1.0);
+        let mut mfs = registry.gather();
+        let req = WriteRequest::from_metric_families(mfs.clone(), None).unwrap();
+        let le = |req: &WriteRequest| {
+            req.timeseries
+                .iter()
+                .filter_map(|ts| ts.label("le").map(str::to_string))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(le(&req), vec!["1e-05", "0.5", "1e+06", "+Inf"]);
+
+        // A registry that reports the `+Inf` bucket itself.
+        let mut inf = prometheus::proto::Bucket::default();
+        inf.set_upper_bound(f64::INFINITY);
+        inf.set_cumulative_count(1);
+        mfs[0].mut_metric()[0]
+            .histogram
+            .mut_or_insert_default()
+            .bucket
+            .push(inf);
+        let req = WriteRequest::from_metric_families(mfs, None).unwrap();
+        assert_eq!(le(&req), vec!["1e-05", "0.5", "1e+06", "+Inf"]);
+    }
+
+    #[test]
+    pub fn can_add_total_suffix_to_counters() {
+        let registry = Registry::new();
+        let requests = Counter::new("requests", "help").unwrap();
+        registry.register(Box::new(requests.clone())).unwrap();
+        let errors = Counter::new("errors_total", "help").unwrap();
+        registry.register(Box::new(errors.clone())).unwrap();
+        let gauge = Gauge::new("in_flight", "help").unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+
+        let names = |options: &ConversionOptions| {
+            WriteRequest::from_metric_families_with_options(&registry.gather(), options)
+                .unwrap()
+                .timeseries
+                .iter()
+                .filter_map(|ts| ts.label(LABEL_NAME).map(str::to_string))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(&ConversionOptions::new()),
+            vec!["errors_total", "in_flight", "requests"]
+        );
+        assert_eq!(
+            names(&ConversionOptions::new().with_total_suffix(true)),
+            vec!["errors_total", "in_flight", "requests_total"]
+        );
+        let req = v2::Request::from_metric_families_with_options(
+            &registry.gather(),
+            &ConversionOptions::new().with_total_suffix(true),
+        )
+        .unwrap();
+        assert!(req.timeseries.iter().all(|ts| ts.metadata.is_some()));
+    }
+
+    #[test]
+    pub fn can_send_created_timestamps() {
+        let registry = Registry::new();
+        let counter = Counter::new("requests_total", "help").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        let histogram = Histogram::with_opts(histogram_opts!("latency", "help")).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        let gauge = Gauge::new("in_flight", "help").unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        let options = ConversionOptions::new().with_created_timestamp(1_600_000_000_000);
+
+        let req =
+            WriteRequest::from_metric_families_with_options(&registry.gather(), &options).unwrap();
+        let created = req
+            .timeseries
+            .iter()
+            .filter(|ts| ts.label(LABEL_NAME).unwrap().ends_with(CREATED_SUFFIX))
+            .map(|ts| (ts.label(LABEL_NAME).unwrap(), ts.samples[0].value))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            created,
+            vec![("latency_created", 1.6e9), ("requests_created", 1.6e9)]
+        );
+
+        let req =
+            v2::Request::from_metric_families_with_options(&registry.gather(), &options).unwrap();
+        for series in &req.timeseries {
+            let name = series.metric_name(&req).unwrap();
+            assert!(!name.ends_with(CREATED_SUFFIX));
+            let expected = if name == "in_flight" {
+                0
+            } else {
+                1_600_000_000_000
+            };
+            assert_eq!(series.created_timestamp, expected, "{name}");
+        }
+    }
+
+    #[test]
+    pub fn sends_created_timestamps_without_metadata() {
+        let registry = Registry::new();
+        let counter = Counter::new("requests_total", "help").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        let gauge = Gauge::new("in_flight", "help").unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        let options = ConversionOptions::new().with_created_timestamp(1_600_000_000_000);
+        let request = || {
+            WriteRequest::from_metric_families_with_options(&registry.gather(), &options).unwrap()
+        };
+        let mut throttle = MetadataThrottle::every(2);
+        let mut tracker = StalenessTracker::new();
+        let mut first = request();
+        throttle.apply(&mut first);
+        tracker.track(&mut first, 1_000);
+        tracker.commit();
+
+        let mut second = request();
+        throttle.apply(&mut second);
+        assert!(second.metadata.is_empty());
+        let req = v2::Request::from(second);
+        let created = req
+            .timeseries
+            .iter()
+            .map(|ts| (ts.metric_name(&req).unwrap(), ts.created_timestamp))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            created,
+            vec![("in_flight", 0), ("requests_total", 1_600_000_000_000)]
+        );
+
+        let stale = v2::Request::from(tracker.stale_markers(2_000));
+        let mut names = stale
+            .timeseries
+            .iter()
+            .map(|ts| ts.metric_name(&stale).unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["in_flight", "requests_total"]);
+    }
+
+    #[test]
+    pub fn can_attach_exemplars() {
+        let registry = Registry::new();
+        let opts = histogram_opts!("latency", "request latency", vec![0.1, 1.0]);
+        let histogram = Histogram::with_opts(opts).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        let counter = Counter::new("requests", "handled requests").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        histogram.observe(0.5);
+        counter.inc();
+        let mut req = WriteRequest::from_metric_families(registry.gather(), None).unwrap();
+        assert!(req.add_exemplar("requests", &[], Exemplar::with_trace_id("abc", 1.0, 1)));
+        assert!(req.add_histogram_exemplar("latency", &[], Exemplar::with_trace_id("def", 0.5, 1)));
+        assert!(!req.add_exemplar("missing", &[], Exemplar::with_trace_id("ghi", 1.0, 1)));
+        let bucket = req
+            .timeseries
+            .iter()
+            .find(|ts| !ts.exemplars.is_empty() && ts.label("le").is_some())
+            .unwrap();
+        assert_eq!(bucket.label("le"), Some("1"));
+        assert_eq!(req.stats().exemplars, 2);
+
+        let v2_req = v2::Request::from(req);
+        let exemplar = v2_req
+            .timeseries
+            .iter()
+            .flat_map(|ts| ts.exemplars.iter())
+            .find(|e| e.value == 0.5)
+            .unwrap();
+        assert_eq!(v2_req.symbol(exemplar.labels_refs[1]), "def");
+    }
+
+    #[test]
+    pub fn can_encode_histogram_with_custom_buckets() {
+        let registry = Registry::new();
+        let opts = histogram_opts!(
+            "my_histogram",
+            "an extra description",
+            vec![10.0, 1000.0, 10000.0]
+        );
+        let histogram = Histogram::with_opts(opts).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        histogram.observe(5.0);
+        histogram.observe(500.0);
+        histogram.observe(5000.0);
+        histogram.observe(50000.0);
+        histogram.observe(50000.0);
+        let options =
+            ConversionOptions::new().with_histogram_encoding(HistogramEncoding::CustomBuckets);
+        let req = WriteRequest::from_metric_families_with_options(&registry.gather(), &options)
+            .expect("Failed to encode histogram");
+        assert_eq!(req.timeseries.len(), 1);
+        let series = req.timeseries.first().unwrap();
+        assert!(series.samples.is_empty());
+        let native = series.histograms.first().unwrap();
+        assert_eq!(native.schema, CUSTOM_BUCKETS_SCHEMA);
+        assert_eq!(native.custom_values, vec![10.0, 1000.0, 10000.0]);
+        assert_eq!(native.count, Some(HistogramCount::CountInt(5)));
+        assert_eq!(native.sum, 105505.0);
+        assert_eq!(
+            native.positive_spans,
+            vec![BucketSpan {
+                offset: 0,
+                length: 4
+            }]
+        );
+        assert_eq!(native.positive_deltas, vec![1, 0, 0, 1]);
+        assert_eq!(req.stats().histograms, 1);
+    }
 }
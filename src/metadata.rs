@@ -0,0 +1,253 @@
+//! Metric metadata: the type, help text and unit of a metric family.
+
+use std::{borrow::Cow, collections::HashMap};
+
+use prometheus::proto::MetricFamily;
+
+use crate::{WriteRequest, COUNT_SUFFIX, CREATED_SUFFIX, SUM_SUFFIX, TOTAL_SUFFIX};
+
+/// The type of a metric family.
+///
+/// .proto:
+/// ```protobuf
+/// enum MetricType {
+///   UNKNOWN        = 0;
+///   COUNTER        = 1;
+///   GAUGE          = 2;
+///   HISTOGRAM      = 3;
+///   GAUGEHISTOGRAM = 4;
+///   SUMMARY        = 5;
+///   INFO           = 6;
+///   STATESET       = 7;
+/// }
+/// ```
+#[derive(prost::Enumeration, Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(i32)]
+pub enum MetadataType {
+    Unknown = 0,
+    Counter = 1,
+    Gauge = 2,
+    Histogram = 3,
+    GaugeHistogram = 4,
+    Summary = 5,
+    Info = 6,
+    Stateset = 7,
+}
+
+impl From<prometheus::proto::MetricType> for MetadataType {
+    fn from(value: prometheus::proto::MetricType) -> Self {
+        match value {
+            prometheus::proto::MetricType::COUNTER => MetadataType::Counter,
+            prometheus::proto::MetricType::GAUGE => MetadataType::Gauge,
+            prometheus::proto::MetricType::SUMMARY => MetadataType::Summary,
+            prometheus::proto::MetricType::HISTOGRAM => MetadataType::Histogram,
+            prometheus::proto::MetricType::UNTYPED => MetadataType::Unknown,
+        }
+    }
+}
+
+/// Metadata of a metric family.
+///
+/// .proto:
+/// ```protobuf
+/// message MetricMetadata {
+///   MetricType type = 1;
+///   string metric_family_name = 2;
+///   string help = 4;
+///   string unit = 5;
+/// }
+/// ```
+#[derive(prost::Message, Clone, Hash, PartialEq, Eq)]
+pub struct MetricMetadata {
+    #[prost(enumeration = "MetadataType", tag = "1")]
+    pub r#type: i32,
+    #[prost(string, tag = "2")]
+    pub metric_family_name: String,
+    #[prost(string, tag = "4")]
+    pub help: String,
+    #[prost(string, tag = "5")]
+    pub unit: String,
+}
+
+impl MetricMetadata {
+    /// Metadata of `mf`.
+    ///
+    /// The prometheus client data model has no notion of units, so `unit` is left empty.
+    pub fn from_metric_family(mf: &MetricFamily) -> Self {
+        Self {
+            r#type: MetadataType::from(mf.get_field_type()) as i32,
+            metric_family_name: mf.name().to_string(),
+            help: mf.help().to_string(),
+            unit: String::new(),
+        }
+    }
+
+    /// True if a series named `series_name` belongs to this metric family.
+    ///
+    /// Accounts for the `_sum`, `_count` and `_bucket` series of summaries and histograms,
+    /// the `_total` suffix of counters, and `_created` series.
+    pub fn describes(&self, series_name: &str) -> bool {
+        let counter = self.r#type == MetadataType::Counter as i32;
+        // OpenMetrics drops `_total` from the name of the `_created` series of a counter.
+        let created_of_total = self
+            .metric_family_name
+            .strip_suffix(TOTAL_SUFFIX)
+            .is_some_and(|family| series_name.strip_prefix(family) == Some(CREATED_SUFFIX));
+        if counter && created_of_total {
+            return true;
+        }
+        match series_name.strip_prefix(self.metric_family_name.as_str()) {
+            Some("") => true,
+            Some(suffix) => {
+                let summary_or_histogram = self.r#type == MetadataType::Summary as i32
+                    || self.r#type == MetadataType::Histogram as i32;
+                (summary_or_histogram
+                    && [SUM_SUFFIX, COUNT_SUFFIX, "_bucket", CREATED_SUFFIX].contains(&suffix))
+                    || (counter && [TOTAL_SUFFIX, CREATED_SUFFIX].contains(&suffix))
+            }
+            None => false,
+        }
+    }
+}
+
+/// Looks up the metadata describing a series by the family names its name can come
+/// from, instead of asking every metadata with [`MetricMetadata::describes`].
+pub(crate) struct MetadataIndex<'a> {
+    metadata: &'a [MetricMetadata],
+    by_family: HashMap<&'a str, usize>,
+}
+
+impl<'a> MetadataIndex<'a> {
+    pub(crate) fn new(metadata: &'a [MetricMetadata]) -> Self {
+        let mut by_family = HashMap::with_capacity(metadata.len());
+        for (i, m) in metadata.iter().enumerate() {
+            by_family.entry(m.metric_family_name.as_str()).or_insert(i);
+        }
+        Self {
+            metadata,
+            by_family,
+        }
+    }
+
+    /// Position of the metadata describing a series named `series_name`.
+    pub(crate) fn find(&self, series_name: &str) -> Option<usize> {
+        let stripped = [
+            SUM_SUFFIX,
+            COUNT_SUFFIX,
+            "_bucket",
+            TOTAL_SUFFIX,
+            CREATED_SUFFIX,
+        ]
+        .into_iter()
+        .filter_map(|suffix| series_name.strip_suffix(suffix))
+        .map(Cow::Borrowed);
+        let total_of_created = series_name
+            .strip_suffix(CREATED_SUFFIX)
+            .map(|family| Cow::Owned(format!("{family}{TOTAL_SUFFIX}")));
+        std::iter::once(Cow::Borrowed(series_name))
+            .chain(stripped)
+            .chain(total_of_created)
+            .filter_map(|family| self.by_family.get(family.as_ref()).copied())
+            .find(|i| self.metadata[*i].describes(series_name))
+    }
+}
+
+/// Sends metadata with only every nth request, like Prometheus does with
+/// `metadata_config.send_interval`, since metadata rarely changes between pushes.
+#[derive(Debug, Clone)]
+pub struct MetadataThrottle {
+    every: u32,
+    pushes: u32,
+}
+
+impl MetadataThrottle {
+    /// Keep metadata on the first request and every `n`th request after it.
+    ///
+    /// `n` of 0 or 1 keeps metadata on every request.
+    pub fn every(n: u32) -> Self {
+        Self {
+            every: n.max(1),
+            pushes: 0,
+        }
+    }
+
+    /// Strip the metadata from `request` unless it is due to be sent.
+    pub fn apply(&mut self, request: &mut WriteRequest) {
+        if self.pushes != 0 {
+            request.metadata.clear();
+        }
+        self.pushes = (self.pushes + 1) % self.every;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use prometheus::{histogram_opts, Histogram, Registry};
+
+    #[test]
+    pub fn describes_histogram_series() {
+        let registry = Registry::new();
+        let opts = histogram_opts!("latency", "request latency");
+        let histogram = Histogram::with_opts(opts).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        histogram.observe(1.0);
+        let req = WriteRequest::from_metric_families(registry.gather(), None).unwrap();
+        assert_eq!(req.metadata.len(), 1);
+        let metadata = req.metadata.first().unwrap();
+        assert_eq!(metadata.r#type, MetadataType::Histogram as i32);
+        assert_eq!(metadata.help, "request latency");
+        assert!(metadata.describes("latency_sum"));
+        assert!(metadata.describes("latency_bucket"));
+        assert!(!metadata.describes("latency_seconds"));
+    }
+
+    #[test]
+    pub fn indexes_metadata_by_family() {
+        let metadata = [
+            ("requests_total", MetadataType::Counter),
+            ("errors", MetadataType::Counter),
+            ("latency", MetadataType::Histogram),
+            ("in_flight", MetadataType::Gauge),
+        ]
+        .map(|(name, r#type)| MetricMetadata {
+            r#type: r#type as i32,
+            metric_family_name: name.to_string(),
+            ..Default::default()
+        });
+        let index = MetadataIndex::new(&metadata);
+        for name in [
+            "requests_total",
+            "requests_created",
+            "errors",
+            "errors_total",
+            "errors_created",
+            "latency_bucket",
+            "latency_sum",
+            "latency_created",
+            "in_flight",
+            "in_flight_sum",
+            "unknown",
+        ] {
+            let scanned = metadata.iter().position(|m| m.describes(name));
+            assert_eq!(index.find(name), scanned, "{name}");
+        }
+    }
+
+    #[test]
+    pub fn throttle_keeps_every_nth_request() {
+        let mut throttle = MetadataThrottle::every(3);
+        let kept = (0..6)
+            .map(|_| {
+                let mut req = WriteRequest {
+                    timeseries: vec![],
+                    metadata: vec![MetricMetadata::default()],
+                };
+                throttle.apply(&mut req);
+                !req.metadata.is_empty()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(kept, vec![true, false, false, true, false, false]);
+    }
+}
@@ -0,0 +1,274 @@
+//! Remote write protocol negotiation.
+//!
+//! Receivers that only speak Remote Write 1.0 answer a 2.0 request with
+//! `415 Unsupported Media Type`, or accept it without saying how much they wrote.
+//! [`ProtocolNegotiator`] tries 2.0 first, falls back to 1.0 on either and remembers
+//! the outcome per endpoint, so later sends go straight to the version the receiver
+//! understands.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use reqwest::{Client, StatusCode};
+
+use crate::{v2, RemoteWriteError, WriteRequest, WriteResponse, WriteStats};
+
+/// Version of the remote write protocol used to encode a request.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    /// `prometheus.WriteRequest`
+    V1,
+    /// `io.prometheus.write.v2.Request`
+    V2,
+}
+
+/// Remembers which protocol version each endpoint accepts.
+///
+/// Cheap to clone; clones share the same per endpoint state.
+#[derive(Debug, Clone)]
+pub struct ProtocolNegotiator {
+    preferred: ProtocolVersion,
+    versions: Arc<RwLock<HashMap<String, ProtocolVersion>>>,
+}
+
+impl Default for ProtocolNegotiator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProtocolNegotiator {
+    /// A negotiator that tries Remote Write 2.0 against endpoints it has not seen before.
+    pub fn new() -> Self {
+        Self::with_preferred(ProtocolVersion::V2)
+    }
+
+    pub fn with_preferred(preferred: ProtocolVersion) -> Self {
+        Self {
+            preferred,
+            versions: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// The version the next send to `endpoint` will use.
+    pub fn version_for(&self, endpoint: &str) -> ProtocolVersion {
+        self.versions
+            .read()
+            .ok()
+            .and_then(|versions| versions.get(endpoint).copied())
+            .unwrap_or(self.preferred)
+    }
+
+    /// Pin `endpoint` to `version`, e.g. when it is known up front.
+    pub fn set_version(&self, endpoint: &str, version: ProtocolVersion) {
+        if let Ok(mut versions) = self.versions.write() {
+            versions.insert(endpoint.to_string(), version);
+        }
+    }
+
+    /// Forget what was learned about `endpoint`, so the next send negotiates again.
+    pub fn reset(&self, endpoint: &str) {
+        if let Ok(mut versions) = self.versions.write() {
+            versions.remove(endpoint);
+        }
+    }
+
+    /// Build the http request for `request` in the given protocol version.
+    pub fn build_http_request(
+        version: ProtocolVersion,
+        request: WriteRequest,
+        client: Client,
+        endpoint: &str,
+        user_agent: &str,
+    ) -> Result<reqwest::Request, RemoteWriteError> {
+        match version {
+            ProtocolVersion::V1 => request.build_http_request(client, endpoint, user_agent),
+            ProtocolVersion::V2 => {
+                v2::Request::from(request).build_http_request(client, endpoint, user_agent)
+            }
+        }
+    }
+
+    /// Send `request` to `endpoint`, using the version negotiated for it.
+    ///
+    /// If a 2.0 request is rejected with `415 Unsupported Media Type`, or accepted
+    /// without the written counts headers every 2.0 receiver sends, the same data is
+    /// re-encoded and sent as 1.0, and the endpoint is remembered as a 1.0 receiver.
+    /// The endpoint is only remembered as a 2.0 receiver once it confirmed a write.
+    /// Returns the response of the last attempt.
+    pub async fn send(
+        &self,
+        client: &Client,
+        endpoint: &str,
+        user_agent: &str,
+        request: WriteRequest,
+    ) -> Result<WriteResponse, RemoteWriteError> {
+        let version = self.version_for(endpoint);
+        if version == ProtocolVersion::V1 {
+            let http_request = request.build_http_request(client.clone(), endpoint, user_agent)?;
+            return WriteResponse::read(version, client.execute(http_request).await?).await;
+        }
+        let http_request = Self::build_http_request(
+            ProtocolVersion::V2,
+            request.clone(),
+            client.clone(),
+            endpoint,
+            user_agent,
+        )?;
+        let response = client.execute(http_request).await?;
+        let confirmed = WriteStats::from_headers(response.headers()).is_some();
+        // A 1.0 receiver may accept a 2.0 body without understanding it.
+        let unconfirmed =
+            response.status().is_success() && !confirmed && !request.timeseries.is_empty();
+        if response.status() != StatusCode::UNSUPPORTED_MEDIA_TYPE && !unconfirmed {
+            if response.status().is_success() {
+                self.set_version(endpoint, ProtocolVersion::V2);
+            }
+            return WriteResponse::read(ProtocolVersion::V2, response).await;
+        }
+        self.set_version(endpoint, ProtocolVersion::V1);
+        let http_request = request.build_http_request(client.clone(), endpoint, user_agent)?;
+        WriteResponse::read(ProtocolVersion::V1, client.execute(http_request).await?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::write_request;
+    use crate::{CONTENT_TYPE, CONTENT_TYPE_V2, HEADER_NAME_SAMPLES_WRITTEN};
+    use pretty_assertions::assert_eq;
+    use wiremock::{
+        matchers::{header, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    #[tokio::test]
+    pub async fn falls_back_to_v1_on_unsupported_media_type() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("content-type", CONTENT_TYPE_V2))
+            .respond_with(ResponseTemplate::new(415))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(header("content-type", CONTENT_TYPE))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(2)
+            .mount(&server)
+            .await;
+        let negotiator = ProtocolNegotiator::new();
+        let client = Client::new();
+        let endpoint = server.uri();
+        let response = negotiator
+            .send(&client, &endpoint, "test", write_request())
+            .await
+            .unwrap();
+        assert_eq!(response.version, ProtocolVersion::V1);
+        assert_eq!(response.status, StatusCode::NO_CONTENT);
+        assert_eq!(negotiator.version_for(&endpoint), ProtocolVersion::V1);
+
+        let response = negotiator
+            .send(&client, &endpoint, "test", write_request())
+            .await
+            .unwrap();
+        assert_eq!(response.version, ProtocolVersion::V1);
+    }
+
+    #[tokio::test]
+    pub async fn keeps_v2_when_accepted() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("content-type", CONTENT_TYPE_V2))
+            .respond_with(
+                ResponseTemplate::new(204).insert_header(HEADER_NAME_SAMPLES_WRITTEN, "0"),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        let negotiator = ProtocolNegotiator::new();
+        let request = write_request();
+        let expected = request.stats();
+        let response = negotiator
+            .send(&Client::new(), &server.uri(), "test", request)
+            .await
+            .unwrap();
+        assert_eq!(response.version, ProtocolVersion::V2);
+        assert_eq!(response.status, StatusCode::NO_CONTENT);
+        assert!(response.is_partial_write(&expected));
+        assert_eq!(negotiator.version_for(&server.uri()), ProtocolVersion::V2);
+    }
+
+    #[tokio::test]
+    pub async fn falls_back_to_v1_when_v2_writes_are_unconfirmed() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("content-type", CONTENT_TYPE_V2))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(header("content-type", CONTENT_TYPE))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let negotiator = ProtocolNegotiator::new();
+        let response = negotiator
+            .send(&Client::new(), &server.uri(), "test", write_request())
+            .await
+            .unwrap();
+        assert_eq!(response.version, ProtocolVersion::V1);
+        assert_eq!(response.status, StatusCode::NO_CONTENT);
+        assert_eq!(negotiator.version_for(&server.uri()), ProtocolVersion::V1);
+    }
+
+    #[tokio::test]
+    pub async fn sends_created_timestamps_on_v2_series() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("content-type", CONTENT_TYPE_V2))
+            .respond_with(
+                ResponseTemplate::new(204).insert_header(HEADER_NAME_SAMPLES_WRITTEN, "3"),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        let registry = prometheus::Registry::new();
+        let counter =
+            prometheus::CounterVec::new(prometheus::Opts::new("requests_total", "help"), &["path"])
+                .unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.with_label_values(&["/a"]).inc();
+        counter.with_label_values(&["/b"]).inc();
+        let gauge = prometheus::Gauge::new("in_flight", "help").unwrap();
+        registry.register(Box::new(gauge)).unwrap();
+        let options = crate::ConversionOptions::new().with_created_timestamp(1_600_000_000_123);
+        let request =
+            WriteRequest::from_metric_families_with_options(&registry.gather(), &options).unwrap();
+        ProtocolNegotiator::new()
+            .send(&Client::new(), &server.uri(), "test", request)
+            .await
+            .unwrap();
+
+        let body = &server.received_requests().await.unwrap()[0].body;
+        let sent = v2::Request::decode_compressed(body).unwrap();
+        let created = sent
+            .timeseries
+            .iter()
+            .map(|ts| (ts.metric_name(&sent).unwrap(), ts.created_timestamp))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            created,
+            vec![
+                ("in_flight", 0),
+                ("requests_total", 1_600_000_000_123),
+                ("requests_total", 1_600_000_000_123),
+            ]
+        );
+    }
+}
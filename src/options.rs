@@ -0,0 +1,175 @@
+//! Options for converting Prometheus metric families into write requests.
+
+use std::{borrow::Cow, sync::Arc};
+
+use prometheus::proto::Metric;
+
+use crate::{Clock, SystemClock, LABEL_NAME, TOTAL_SUFFIX};
+
+/// How classic histograms are encoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HistogramEncoding {
+    /// One series per bucket with an `le` label, plus `_sum` and `_count` series.
+    #[default]
+    Classic,
+    /// A single native histogram series with custom bucket boundaries (schema -53).
+    ///
+    /// Only use this against receivers that support native histograms with custom buckets.
+    CustomBuckets,
+}
+
+/// What to do when a custom label has the same name as a label of the metric.
+///
+/// Mirrors how Prometheus applies `external_labels` and `honor_labels`. It never
+/// applies to `__name__`: a custom label with that name is always dropped, since
+/// replacing or renaming the metric name of only some series of a family would
+/// split it apart.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LabelCollision {
+    /// Keep the label of the metric and drop the custom label, like `external_labels`.
+    #[default]
+    KeepMetric,
+    /// Replace the label of the metric with the custom label, like `honor_labels: false`
+    /// without keeping the original value.
+    Override,
+    /// Set the custom label and keep the label of the metric as `exported_<name>`,
+    /// like `honor_labels: false`.
+    Rename,
+}
+
+/// Options for [`WriteRequest::from_metric_families_with_options`](crate::WriteRequest::from_metric_families_with_options).
+#[derive(Debug, Clone)]
+pub struct ConversionOptions {
+    /// Labels added to every series.
+    ///
+    /// A custom `__name__` label is ignored, whatever the [`LabelCollision`] policy:
+    /// the metric name always comes from the metric family.
+    pub custom_labels: Vec<(String, String)>,
+    /// How custom labels colliding with labels of a metric are resolved.
+    pub label_collision: LabelCollision,
+    pub histogram_encoding: HistogramEncoding,
+    /// Use the timestamp a metric carries, if any, instead of the time of conversion.
+    ///
+    /// Defaults to `true`, like `honor_timestamps` in a Prometheus scrape config.
+    pub honor_timestamps: bool,
+    /// Make sure counter series names end in `_total`, as OpenMetrics requires.
+    pub total_suffix: bool,
+    /// When counters, summaries and histograms started counting, in milliseconds since the epoch.
+    ///
+    /// If set, a `<name>_created` series is sent for each of them (1.0), or the
+    /// `created_timestamp` of their series is set (2.0). The metric families of the
+    /// `prometheus` crate don't record when a metric was created, so this is one
+    /// timestamp for all of them, typically the start of the process.
+    pub created_timestamp: Option<i64>,
+    /// Where the timestamp of metrics without one of their own comes from.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for ConversionOptions {
+    fn default() -> Self {
+        Self {
+            custom_labels: Vec::new(),
+            label_collision: LabelCollision::default(),
+            histogram_encoding: HistogramEncoding::default(),
+            honor_timestamps: true,
+            total_suffix: false,
+            created_timestamp: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl ConversionOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_custom_labels(mut self, custom_labels: Vec<(String, String)>) -> Self {
+        self.custom_labels = custom_labels;
+        self
+    }
+
+    pub fn with_label_collision(mut self, label_collision: LabelCollision) -> Self {
+        self.label_collision = label_collision;
+        self
+    }
+
+    pub fn with_histogram_encoding(mut self, histogram_encoding: HistogramEncoding) -> Self {
+        self.histogram_encoding = histogram_encoding;
+        self
+    }
+
+    pub fn with_honor_timestamps(mut self, honor_timestamps: bool) -> Self {
+        self.honor_timestamps = honor_timestamps;
+        self
+    }
+
+    pub fn with_total_suffix(mut self, total_suffix: bool) -> Self {
+        self.total_suffix = total_suffix;
+        self
+    }
+
+    pub fn with_created_timestamp(mut self, created_timestamp: i64) -> Self {
+        self.created_timestamp = Some(created_timestamp);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// The series name of a counter called `name`.
+    pub(crate) fn counter_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        if self.total_suffix && !name.ends_with(TOTAL_SUFFIX) {
+            Cow::Owned(format!("{name}{TOTAL_SUFFIX}"))
+        } else {
+            Cow::Borrowed(name)
+        }
+    }
+
+    /// The labels of `metric` and the custom labels, with collisions resolved, sorted by name.
+    ///
+    /// Borrows from `metric` and these options wherever possible. The metric name is
+    /// not included; custom labels never replace it.
+    pub(crate) fn series_labels<'a>(&'a self, metric: &'a Metric) -> Vec<(Cow<'a, str>, &'a str)> {
+        let mut labels = Vec::with_capacity(metric.get_label().len() + self.custom_labels.len());
+        labels.extend(
+            metric
+                .get_label()
+                .iter()
+                .map(|l| (Cow::Borrowed(l.name()), l.value())),
+        );
+        for (k, v) in &self.custom_labels {
+            if k == LABEL_NAME {
+                continue;
+            }
+            let Some(existing) = labels.iter().position(|(name, _)| name == k) else {
+                labels.push((Cow::Borrowed(k.as_str()), v.as_str()));
+                continue;
+            };
+            match self.label_collision {
+                LabelCollision::KeepMetric => {}
+                LabelCollision::Override => labels[existing].1 = v.as_str(),
+                LabelCollision::Rename => {
+                    let mut exported = format!("exported_{k}");
+                    while labels.iter().any(|(name, _)| *name == exported) {
+                        exported = format!("exported_{exported}");
+                    }
+                    labels[existing].0 = Cow::Owned(exported);
+                    labels.push((Cow::Borrowed(k.as_str()), v.as_str()));
+                }
+            }
+        }
+        labels.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        labels
+    }
+
+    /// The timestamp of the samples of `metric`, converted at `now`.
+    pub(crate) fn sample_timestamp(&self, metric: &Metric, now: i64) -> i64 {
+        match metric.timestamp_ms {
+            Some(timestamp) if self.honor_timestamps => timestamp,
+            _ => now,
+        }
+    }
+}
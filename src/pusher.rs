@@ -0,0 +1,444 @@
+//! Pushing a registry on a schedule.
+//!
+//! [`Pusher`] runs the loop every sender otherwise writes by hand: gather the
+//! registry, convert it with [`WriteRequest::from_metric_families_with_options`]
+//! and send it with a [`RemoteWriteClient`], once per interval. The spawned task
+//! is controlled through a [`PusherHandle`], which can also shut it down with a
+//! final push so the last interval isn't lost.
+
+use std::{sync::Arc, time::Duration};
+
+use prometheus::Registry;
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::{sleep_until, Instant},
+};
+
+use crate::{
+    retry::jittered, ConversionOptions, MetadataThrottle, ProtocolVersion, RemoteWriteClient,
+    RemoteWriteError, StalenessTracker, WriteOutcome, WriteRequest,
+};
+
+/// When the next push is due if the interval is too long to represent, about 30 years from now.
+const FAR_FUTURE: Duration = Duration::from_secs(86_400 * 365 * 30);
+
+/// Called with the result of every scheduled push.
+pub type PushCallback = Arc<dyn Fn(&Result<WriteOutcome, RemoteWriteError>) + Send + Sync>;
+
+/// Pushes a registry to a remote write endpoint on a schedule.
+pub struct Pusher {
+    registry: Registry,
+    client: RemoteWriteClient,
+    interval: Duration,
+    jitter: f64,
+    options: ConversionOptions,
+    on_push: Option<PushCallback>,
+    metadata_throttle: Option<MetadataThrottle>,
+    /// Tracks the series pushed, if stale markers are enabled.
+    staleness: Option<StalenessTracker>,
+}
+
+impl Pusher {
+    /// A pusher sending `registry` through `client` every `interval`.
+    pub fn new(registry: Registry, client: RemoteWriteClient, interval: Duration) -> Self {
+        Self {
+            registry,
+            client,
+            interval,
+            jitter: 0.0,
+            options: ConversionOptions::default(),
+            on_push: None,
+            metadata_throttle: None,
+            staleness: None,
+        }
+    }
+
+    /// Shorten every interval by a random fraction of up to `jitter`, between 0 and 1,
+    /// so many instances started together don't push in lockstep.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_options(mut self, options: ConversionOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Report the result of every scheduled push to `on_push`, e.g. to log failures.
+    pub fn with_on_push(
+        mut self,
+        on_push: impl Fn(&Result<WriteOutcome, RemoteWriteError>) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_push = Some(Arc::new(on_push));
+        self
+    }
+
+    /// Send metadata with only every `n`th push, see [`MetadataThrottle`]. Remote Write 2.0
+    /// carries metadata on every series, so it is always sent with 2.0.
+    pub fn with_metadata_every(mut self, n: u32) -> Self {
+        self.metadata_throttle = Some(MetadataThrottle::every(n));
+        self
+    }
+
+    /// Mark series as stale when they are no longer gathered, e.g. because they were
+    /// removed from a `CounterVec`, and mark every series as stale on shutdown. Without
+    /// stale markers, receivers show the last value for another 5 minutes.
+    pub fn with_stale_markers(mut self, stale_markers: bool) -> Self {
+        self.staleness = stale_markers.then(StalenessTracker::new);
+        self
+    }
+
+    /// Gather, convert and send the registry once, throttling metadata and adding
+    /// stale markers for series that vanished, if enabled.
+    pub async fn push(&mut self) -> Result<WriteOutcome, RemoteWriteError> {
+        let mut request = WriteRequest::from_metric_families_with_options(
+            &self.registry.gather(),
+            &self.options,
+        )?;
+        if let Some(throttle) = &mut self.metadata_throttle {
+            if self.client.protocol_version() == ProtocolVersion::V1 {
+                throttle.apply(&mut request);
+            }
+        }
+        if let Some(staleness) = &mut self.staleness {
+            staleness.track(&mut request, self.options.clock.now_ms()?);
+        }
+        let outcome = self.client.send(request).await?;
+        if let Some(staleness) = self.staleness.as_mut().filter(|_| outcome.is_success()) {
+            staleness.commit();
+        }
+        Ok(outcome)
+    }
+
+    /// Answer pushes already requested, push one last time and send the stale markers.
+    async fn shutdown(
+        &mut self,
+        commands: &mut mpsc::UnboundedReceiver<Command>,
+    ) -> Result<(), RemoteWriteError> {
+        commands.close();
+        while let Ok(command) = commands.try_recv() {
+            if let Command::Push(reply) = command {
+                let _ = reply.send(self.push().await);
+            }
+        }
+        let pushed = self.push().await.and_then(WriteOutcome::into_result);
+        let Some(staleness) = self.staleness.as_mut().filter(|s| !s.is_empty()) else {
+            return pushed.map(drop);
+        };
+        let stale = staleness.stale_markers(self.options.clock.now_ms()?);
+        let marked = self
+            .client
+            .send(stale)
+            .await
+            .and_then(WriteOutcome::into_result);
+        pushed.and(marked).map(drop)
+    }
+
+    /// Start pushing on the current tokio runtime. The first push happens one interval from now.
+    pub fn spawn(self) -> PusherHandle {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let task = tokio::spawn(self.run(receiver));
+        PusherHandle { commands, task }
+    }
+
+    fn next_push(&self) -> Instant {
+        let now = Instant::now();
+        // An interval too long to add up, e.g. `Duration::MAX` to pause, is as good as never.
+        now.checked_add(jittered(self.interval, self.jitter))
+            .unwrap_or_else(|| now + FAR_FUTURE)
+    }
+
+    async fn run(mut self, mut commands: mpsc::UnboundedReceiver<Command>) {
+        let mut next = self.next_push();
+        loop {
+            tokio::select! {
+                _ = sleep_until(next) => {
+                    let result = self.push().await;
+                    if let Some(on_push) = &self.on_push {
+                        on_push(&result);
+                    }
+                    next = self.next_push();
+                }
+                command = commands.recv() => match command {
+                    Some(Command::Push(reply)) => {
+                        let _ = reply.send(self.push().await);
+                        next = self.next_push();
+                    }
+                    Some(Command::SetInterval(interval)) => {
+                        self.interval = interval;
+                        next = self.next_push();
+                    }
+                    Some(Command::Shutdown(reply)) => {
+                        let _ = reply.send(self.shutdown(&mut commands).await);
+                        return;
+                    }
+                    Some(Command::Stop) | None => return,
+                },
+            }
+        }
+    }
+}
+
+enum Command {
+    Push(oneshot::Sender<Result<WriteOutcome, RemoteWriteError>>),
+    SetInterval(Duration),
+    Shutdown(oneshot::Sender<Result<(), RemoteWriteError>>),
+    Stop,
+}
+
+/// Controls a spawned [`Pusher`].
+///
+/// Dropping the handle stops the pusher as well, without waiting for it.
+pub struct PusherHandle {
+    commands: mpsc::UnboundedSender<Command>,
+    task: JoinHandle<()>,
+}
+
+impl PusherHandle {
+    /// Push right away and wait for the result. The next scheduled push is one interval later.
+    pub async fn push_now(&self) -> Result<WriteOutcome, RemoteWriteError> {
+        let (reply, result) = oneshot::channel();
+        self.commands
+            .send(Command::Push(reply))
+            .map_err(|_| RemoteWriteError::Stopped)?;
+        result.await.map_err(|_| RemoteWriteError::Stopped)?
+    }
+
+    /// Push every `interval` from now on.
+    pub fn set_interval(&self, interval: Duration) {
+        let _ = self.commands.send(Command::SetInterval(interval));
+    }
+
+    /// Stop pushing, waiting for a push in progress to finish.
+    pub async fn stop(self) {
+        let _ = self.commands.send(Command::Stop);
+        let _ = self.task.await;
+    }
+
+    /// Stop pushing, but first answer pushes already requested with
+    /// [`push_now`](Self::push_now), gather and send one last time and, if enabled,
+    /// send stale markers for every series pushed last.
+    ///
+    /// Gives up with [`RemoteWriteError::DeadlineExceeded`] if that, including a push
+    /// in progress and its retries, takes longer than `deadline`. Otherwise fails if
+    /// the final push or the stale markers weren't written.
+    pub async fn shutdown(self, deadline: Duration) -> Result<(), RemoteWriteError> {
+        let (reply, result) = oneshot::channel();
+        self.commands
+            .send(Command::Shutdown(reply))
+            .map_err(|_| RemoteWriteError::Stopped)?;
+        match tokio::time::timeout(deadline, result).await {
+            Ok(result) => result.map_err(|_| RemoteWriteError::Stopped)?,
+            Err(_) => {
+                self.task.abort();
+                Err(RemoteWriteError::DeadlineExceeded)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FixedClock, LABEL_NAME, STALE_NAN};
+    use pretty_assertions::assert_eq;
+    use prometheus::{IntCounter, IntCounterVec, Opts};
+    use reqwest::Client;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+    async fn server() -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    fn registry() -> Registry {
+        let registry = Registry::new();
+        let counter = IntCounter::with_opts(Opts::new("requests_total", "requests")).unwrap();
+        counter.inc();
+        registry.register(Box::new(counter)).unwrap();
+        registry
+    }
+
+    async fn pushed(server: &MockServer) -> Vec<WriteRequest> {
+        server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|r| WriteRequest::decode_compressed(&r.body).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    pub async fn pushes_on_schedule() {
+        let server = server().await;
+        let pushes = Arc::new(AtomicUsize::new(0));
+        let counted = pushes.clone();
+        let client = RemoteWriteClient::new(Client::new(), &server.uri(), "test");
+        let handle = Pusher::new(registry(), client, Duration::from_millis(20))
+            .with_jitter(0.5)
+            .with_on_push(move |result| {
+                assert!(result.as_ref().unwrap().is_success());
+                counted.fetch_add(1, Ordering::SeqCst);
+            })
+            .spawn();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        handle.stop().await;
+
+        let requests = pushed(&server).await;
+        assert!(requests.len() >= 2, "{}", requests.len());
+        assert_eq!(pushes.load(Ordering::SeqCst), requests.len());
+        assert_eq!(
+            requests[0].timeseries[0].label(LABEL_NAME),
+            Some("requests_total")
+        );
+    }
+
+    #[tokio::test]
+    pub async fn pushes_on_demand_and_changes_interval() {
+        let server = server().await;
+        let client = RemoteWriteClient::new(Client::new(), &server.uri(), "test");
+        let handle = Pusher::new(registry(), client, Duration::from_secs(3600)).spawn();
+        assert!(handle.push_now().await.unwrap().is_success());
+        assert_eq!(pushed(&server).await.len(), 1);
+
+        handle.set_interval(Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        handle.stop().await;
+        assert!(pushed(&server).await.len() > 2);
+    }
+
+    #[tokio::test]
+    pub async fn shuts_down_with_a_final_push_and_stale_markers() {
+        let server = server().await;
+        let client = RemoteWriteClient::new(Client::new(), &server.uri(), "test");
+        let handle = Pusher::new(registry(), client, Duration::from_secs(3600))
+            .with_options(ConversionOptions::new().with_clock(FixedClock(1_000)))
+            .with_stale_markers(true)
+            .spawn();
+        handle.push_now().await.unwrap();
+        handle.shutdown(Duration::from_secs(5)).await.unwrap();
+
+        let requests = pushed(&server).await;
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[1], requests[0]);
+        let stale = &requests[2].timeseries;
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].labels, requests[0].timeseries[0].labels);
+        assert!(stale[0].samples[0].is_stale());
+        assert_eq!(stale[0].samples[0].value.to_bits(), STALE_NAN.to_bits());
+        assert_eq!(stale[0].samples[0].timestamp, 1_001);
+    }
+
+    #[tokio::test]
+    pub async fn gives_up_shutting_down_after_the_deadline() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(204).set_delay(Duration::from_secs(10)))
+            .mount(&server)
+            .await;
+        let client = RemoteWriteClient::new(Client::new(), &server.uri(), "test");
+        let handle = Pusher::new(registry(), client, Duration::from_secs(3600)).spawn();
+        let result = handle.shutdown(Duration::from_millis(100)).await;
+        assert!(matches!(result, Err(RemoteWriteError::DeadlineExceeded)));
+    }
+
+    #[tokio::test]
+    pub async fn resends_stale_markers_after_a_failed_push() {
+        let server = MockServer::start().await;
+        for status in [204, 503] {
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(status))
+                .up_to_n_times(1)
+                .mount(&server)
+                .await;
+        }
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+        let registry = Registry::new();
+        let counter =
+            IntCounterVec::new(Opts::new("requests_total", "requests"), &["path"]).unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.with_label_values(&["/a"]).inc();
+        counter.with_label_values(&["/b"]).inc();
+        let client = RemoteWriteClient::new(Client::new(), &server.uri(), "test");
+        let handle = Pusher::new(registry, client, Duration::from_secs(3600))
+            .with_stale_markers(true)
+            .spawn();
+        assert!(handle.push_now().await.unwrap().is_success());
+        counter.remove_label_values(&["/b"]).unwrap();
+        assert!(handle.push_now().await.unwrap().is_retryable());
+        assert!(handle.push_now().await.unwrap().is_success());
+        handle.stop().await;
+
+        let stale = |request: &WriteRequest| {
+            request
+                .timeseries
+                .iter()
+                .filter(|ts| ts.samples[0].is_stale())
+                .map(|ts| ts.label("path").unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        let requests = pushed(&server).await;
+        assert_eq!(requests.len(), 3);
+        assert_eq!(stale(&requests[1]), vec!["/b"]);
+        assert_eq!(stale(&requests[2]), vec!["/b"]);
+    }
+
+    #[tokio::test]
+    pub async fn pauses_with_a_huge_interval() {
+        let server = server().await;
+        let client = RemoteWriteClient::new(Client::new(), &server.uri(), "test");
+        let handle = Pusher::new(registry(), client, Duration::MAX).spawn();
+        handle.set_interval(Duration::MAX);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(handle.push_now().await.unwrap().is_success());
+        handle.stop().await;
+        assert_eq!(pushed(&server).await.len(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn throttles_metadata() {
+        let server = server().await;
+        let client = RemoteWriteClient::new(Client::new(), &server.uri(), "test");
+        let handle = Pusher::new(registry(), client, Duration::from_secs(3600))
+            .with_metadata_every(2)
+            .spawn();
+        for _ in 0..3 {
+            handle.push_now().await.unwrap();
+        }
+        handle.stop().await;
+        let metadata = pushed(&server)
+            .await
+            .iter()
+            .map(|r| r.metadata.len())
+            .collect::<Vec<_>>();
+        assert_eq!(metadata, vec![1, 0, 1]);
+    }
+
+    #[tokio::test]
+    pub async fn throttles_metadata_when_pushed_directly() {
+        let server = server().await;
+        let client = RemoteWriteClient::new(Client::new(), &server.uri(), "test");
+        let mut pusher =
+            Pusher::new(registry(), client, Duration::from_secs(3600)).with_metadata_every(2);
+        for _ in 0..2 {
+            assert!(pusher.push().await.unwrap().is_success());
+        }
+        let metadata = pushed(&server)
+            .await
+            .iter()
+            .map(|r| r.metadata.len())
+            .collect::<Vec<_>>();
+        assert_eq!(metadata, vec![1, 0]);
+    }
+}
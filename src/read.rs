@@ -0,0 +1,625 @@
+//! Prometheus remote read.
+//!
+//! A [`ReadRequest`] holds one or more queries, each selecting series by label
+//! matchers over a time range. Receivers answer either with sampled results
+//! (a snappy compressed [`ReadResponse`]) or, if the request accepts it, a stream
+//! of [`ChunkedReadResponse`] frames holding XOR encoded chunks. Both are decoded
+//! into the same [`TimeSeries`] and [`Sample`](crate::Sample) types used for writing.
+//! See https://prometheus.io/docs/prometheus/latest/querying/remote_read_api/.
+
+use reqwest::Client;
+
+use crate::{
+    chunkenc::decode_xor_chunk, decode::decompress, DecodeError, Label, RemoteWriteError,
+    TimeSeries, CONTENT_TYPE, DEFAULT_MAX_DECOMPRESSED_SIZE, LABEL_NAME,
+};
+
+pub const HEADER_NAME_REMOTE_READ_VERSION: &str = "X-Prometheus-Remote-Read-Version";
+pub const REMOTE_READ_VERSION_01: &str = "0.1.0";
+/// Content type of a streamed [`ChunkedReadResponse`] body.
+pub const CONTENT_TYPE_STREAMED: &str =
+    "application/x-streamed-protobuf; proto=prometheus.ChunkedReadResponse";
+
+/// How a receiver may answer a read request.
+///
+/// .proto:
+/// ```protobuf
+/// enum ResponseType {
+///   SAMPLES             = 0;
+///   STREAMED_XOR_CHUNKS = 1;
+/// }
+/// ```
+#[derive(prost::Enumeration, Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(i32)]
+pub enum ResponseType {
+    Samples = 0,
+    StreamedXorChunks = 1,
+}
+
+/// The kind of comparison a label matcher makes.
+///
+/// .proto:
+/// ```protobuf
+/// enum Type {
+///   EQ  = 0;
+///   NEQ = 1;
+///   RE  = 2;
+///   NRE = 3;
+/// }
+/// ```
+#[derive(prost::Enumeration, Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(i32)]
+pub enum MatchType {
+    Eq = 0,
+    Neq = 1,
+    Re = 2,
+    Nre = 3,
+}
+
+/// Selects series by the value of a label.
+///
+/// .proto:
+/// ```protobuf
+/// message LabelMatcher {
+///   Type   type  = 1;
+///   string name  = 2;
+///   string value = 3;
+/// }
+/// ```
+#[derive(prost::Message, Clone, Hash, PartialEq, Eq)]
+pub struct LabelMatcher {
+    #[prost(enumeration = "MatchType", tag = "1")]
+    pub r#type: i32,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    #[prost(string, tag = "3")]
+    pub value: String,
+}
+
+impl LabelMatcher {
+    pub fn new(r#type: MatchType, name: &str, value: &str) -> Self {
+        Self {
+            r#type: r#type as i32,
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    /// Matches series whose metric name is `name`.
+    pub fn metric_name(name: &str) -> Self {
+        Self::new(MatchType::Eq, LABEL_NAME, name)
+    }
+
+    pub fn eq(name: &str, value: &str) -> Self {
+        Self::new(MatchType::Eq, name, value)
+    }
+
+    pub fn neq(name: &str, value: &str) -> Self {
+        Self::new(MatchType::Neq, name, value)
+    }
+
+    /// Matches label values against an RE2 regular expression.
+    pub fn re(name: &str, value: &str) -> Self {
+        Self::new(MatchType::Re, name, value)
+    }
+
+    pub fn nre(name: &str, value: &str) -> Self {
+        Self::new(MatchType::Nre, name, value)
+    }
+}
+
+/// Hints about the query the data is read for, which receivers may use to pick or aggregate data.
+///
+/// .proto:
+/// ```protobuf
+/// message ReadHints {
+///   int64 step_ms = 1;
+///   string func = 2;
+///   int64 start_ms = 3;
+///   int64 end_ms = 4;
+///   repeated string grouping = 5;
+///   bool by = 6;
+///   int64 range_ms = 7;
+/// }
+/// ```
+#[derive(prost::Message, Clone, PartialEq)]
+pub struct ReadHints {
+    #[prost(int64, tag = "1")]
+    pub step_ms: i64,
+    #[prost(string, tag = "2")]
+    pub func: String,
+    #[prost(int64, tag = "3")]
+    pub start_ms: i64,
+    #[prost(int64, tag = "4")]
+    pub end_ms: i64,
+    #[prost(string, repeated, tag = "5")]
+    pub grouping: Vec<String>,
+    #[prost(bool, tag = "6")]
+    pub by: bool,
+    #[prost(int64, tag = "7")]
+    pub range_ms: i64,
+}
+
+/// A query.
+///
+/// .proto:
+/// ```protobuf
+/// message Query {
+///   int64 start_timestamp_ms = 1;
+///   int64 end_timestamp_ms = 2;
+///   repeated prometheus.LabelMatcher matchers = 3;
+///   prometheus.ReadHints hints = 4;
+/// }
+/// ```
+#[derive(prost::Message, Clone, PartialEq)]
+pub struct Query {
+    #[prost(int64, tag = "1")]
+    pub start_timestamp_ms: i64,
+    #[prost(int64, tag = "2")]
+    pub end_timestamp_ms: i64,
+    #[prost(message, repeated, tag = "3")]
+    pub matchers: Vec<LabelMatcher>,
+    #[prost(message, optional, tag = "4")]
+    pub hints: Option<ReadHints>,
+}
+
+impl Query {
+    /// Select the series matching all of `matchers` between `start_timestamp_ms` and `end_timestamp_ms`.
+    pub fn new(
+        start_timestamp_ms: i64,
+        end_timestamp_ms: i64,
+        matchers: Vec<LabelMatcher>,
+    ) -> Self {
+        Self {
+            start_timestamp_ms,
+            end_timestamp_ms,
+            matchers,
+            hints: None,
+        }
+    }
+}
+
+/// A read request.
+///
+/// .proto:
+/// ```protobuf
+/// message ReadRequest {
+///   repeated Query queries = 1;
+///   repeated ResponseType accepted_response_types = 2;
+/// }
+/// ```
+#[derive(prost::Message, Clone, PartialEq)]
+pub struct ReadRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub queries: Vec<Query>,
+    /// In order of preference. Empty means only sampled responses are accepted.
+    #[prost(enumeration = "ResponseType", repeated, tag = "2")]
+    pub accepted_response_types: Vec<i32>,
+}
+
+/// The series matching one query.
+///
+/// .proto:
+/// ```protobuf
+/// message QueryResult {
+///   repeated prometheus.TimeSeries timeseries = 1;
+/// }
+/// ```
+#[derive(prost::Message, Clone, PartialEq)]
+pub struct QueryResult {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+/// A sampled read response, with one result per query, in the order of the queries.
+///
+/// .proto:
+/// ```protobuf
+/// message ReadResponse {
+///   repeated QueryResult results = 1;
+/// }
+/// ```
+#[derive(prost::Message, Clone, PartialEq)]
+pub struct ReadResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub results: Vec<QueryResult>,
+}
+
+/// The encoding of a chunk.
+///
+/// .proto:
+/// ```protobuf
+/// enum Encoding {
+///   UNKNOWN         = 0;
+///   XOR             = 1;
+///   HISTOGRAM       = 2;
+///   FLOAT_HISTOGRAM = 3;
+/// }
+/// ```
+#[derive(prost::Enumeration, Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(i32)]
+pub enum ChunkEncoding {
+    Unknown = 0,
+    Xor = 1,
+    Histogram = 2,
+    FloatHistogram = 3,
+}
+
+/// A TSDB chunk holding the samples of one series between two timestamps.
+///
+/// .proto:
+/// ```protobuf
+/// message Chunk {
+///   int64 min_time_ms = 1;
+///   int64 max_time_ms = 2;
+///   Encoding type = 3;
+///   bytes data = 4;
+/// }
+/// ```
+#[derive(prost::Message, Clone, PartialEq)]
+pub struct Chunk {
+    #[prost(int64, tag = "1")]
+    pub min_time_ms: i64,
+    #[prost(int64, tag = "2")]
+    pub max_time_ms: i64,
+    #[prost(enumeration = "ChunkEncoding", tag = "3")]
+    pub r#type: i32,
+    #[prost(bytes = "vec", tag = "4")]
+    pub data: Vec<u8>,
+}
+
+/// A series with its samples as chunks.
+///
+/// .proto:
+/// ```protobuf
+/// message ChunkedSeries {
+///   repeated Label labels = 1;
+///   repeated Chunk chunks = 2;
+/// }
+/// ```
+#[derive(prost::Message, Clone, PartialEq)]
+pub struct ChunkedSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub chunks: Vec<Chunk>,
+}
+
+/// One frame of a streamed read response.
+///
+/// .proto:
+/// ```protobuf
+/// message ChunkedReadResponse {
+///   repeated prometheus.ChunkedSeries chunked_series = 1;
+///   int64 query_index = 2;
+/// }
+/// ```
+#[derive(prost::Message, Clone, PartialEq)]
+pub struct ChunkedReadResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub chunked_series: Vec<ChunkedSeries>,
+    /// Index of the query in the request this frame answers.
+    #[prost(int64, tag = "2")]
+    pub query_index: i64,
+}
+
+impl ReadRequest {
+    /// A request for sampled results of `queries`.
+    pub fn new(queries: Vec<Query>) -> Self {
+        Self {
+            queries,
+            accepted_response_types: vec![ResponseType::Samples as i32],
+        }
+    }
+
+    /// Prefer a streamed response of XOR chunks, falling back to sampled results.
+    pub fn streamed(mut self) -> Self {
+        self.accepted_response_types = vec![
+            ResponseType::StreamedXorChunks as i32,
+            ResponseType::Samples as i32,
+        ];
+        self
+    }
+
+    /// Encode this read request as a protobuf message.
+    pub fn encode_proto3(self) -> Vec<u8> {
+        prost::Message::encode_to_vec(&self)
+    }
+
+    pub fn encode_compressed(self) -> Result<Vec<u8>, RemoteWriteError> {
+        Ok(snap::raw::Encoder::new().compress_vec(&self.encode_proto3())?)
+    }
+
+    pub fn build_http_request(
+        self,
+        client: Client,
+        endpoint: &str,
+        user_agent: &str,
+    ) -> Result<reqwest::Request, RemoteWriteError> {
+        let body = self.encode_compressed()?;
+        client
+            .post(endpoint)
+            .header(reqwest::header::CONTENT_TYPE, CONTENT_TYPE)
+            .header(HEADER_NAME_REMOTE_READ_VERSION, REMOTE_READ_VERSION_01)
+            .header(reqwest::header::CONTENT_ENCODING, "snappy")
+            .header(reqwest::header::USER_AGENT, user_agent)
+            .body(body)
+            .build()
+            .map_err(RemoteWriteError::HttpBuild)
+    }
+
+    /// Send this request and decode the response, whichever type the receiver picked.
+    pub async fn send(
+        self,
+        client: &Client,
+        endpoint: &str,
+        user_agent: &str,
+    ) -> Result<ReadResponse, RemoteWriteError> {
+        let queries = self.queries.len();
+        let http_request = self.build_http_request(client.clone(), endpoint, user_agent)?;
+        let response = client.execute(http_request).await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await?;
+            return Err(RemoteWriteError::Status { status, body });
+        }
+        let streamed = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.starts_with("application/x-streamed-protobuf"))
+            .unwrap_or(false);
+        let body = response.bytes().await?;
+        if streamed {
+            ReadResponse::decode_stream(&body, queries)
+        } else {
+            ReadResponse::decode_compressed(&body)
+        }
+    }
+}
+
+impl ReadResponse {
+    /// Decode a sampled response body.
+    pub fn decode_compressed(buf: &[u8]) -> Result<Self, RemoteWriteError> {
+        Ok(prost::Message::decode(
+            decompress(buf, DEFAULT_MAX_DECOMPRESSED_SIZE)?.as_slice(),
+        )?)
+    }
+
+    /// Decode a streamed response body answering `queries` queries, decoding the
+    /// chunks of every series into samples.
+    ///
+    /// Series split over several frames are joined back together. Frames answering a
+    /// query that wasn't asked are refused.
+    pub fn decode_stream(buf: &[u8], queries: usize) -> Result<Self, RemoteWriteError> {
+        let mut response = ReadResponse {
+            results: vec![QueryResult::default(); queries],
+        };
+        for frame in ChunkedReadResponse::decode_stream(buf)? {
+            let result = usize::try_from(frame.query_index)
+                .ok()
+                .and_then(|index| response.results.get_mut(index))
+                .ok_or(DecodeError::InvalidChunk("query index out of range"))?;
+            for chunked in frame.chunked_series {
+                let mut samples = Vec::new();
+                for chunk in &chunked.chunks {
+                    if chunk.r#type != ChunkEncoding::Xor as i32 {
+                        return Err(DecodeError::InvalidChunk("unsupported chunk encoding").into());
+                    }
+                    samples.extend(decode_xor_chunk(&chunk.data)?);
+                }
+                match result.timeseries.last_mut() {
+                    Some(last) if last.labels == chunked.labels => last.samples.extend(samples),
+                    _ => result.timeseries.push(TimeSeries {
+                        labels: chunked.labels,
+                        samples,
+                        ..Default::default()
+                    }),
+                }
+            }
+        }
+        Ok(response)
+    }
+}
+
+impl ChunkedReadResponse {
+    /// Split a streamed response body into its frames.
+    ///
+    /// Every frame is the uvarint encoded length of the message, a big-endian
+    /// CRC32C (Castagnoli) checksum of the message, and the message itself.
+    pub fn decode_stream(mut buf: &[u8]) -> Result<Vec<Self>, RemoteWriteError> {
+        let mut frames = Vec::new();
+        while !buf.is_empty() {
+            let size = prost::encoding::decode_varint(&mut buf)?;
+            // The length comes from the server; don't trust it before comparing to what's left.
+            let end = usize::try_from(size)
+                .ok()
+                .and_then(|size| size.checked_add(4))
+                .filter(|end| *end <= buf.len())
+                .ok_or(DecodeError::InvalidChunk("truncated frame"))?;
+            let checksum = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+            let message = &buf[4..end];
+            if crc32c(message) != checksum {
+                return Err(DecodeError::InvalidChunk("frame checksum mismatch").into());
+            }
+            frames.push(prost::Message::decode(message)?);
+            buf = &buf[end..];
+        }
+        Ok(frames)
+    }
+}
+
+/// CRC32 with the Castagnoli polynomial.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chunkenc::tests::encode_xor_chunk, Sample};
+    use pretty_assertions::assert_eq;
+    use wiremock::{
+        matchers::{header, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn series(name: &str, samples: &[(i64, f64)]) -> TimeSeries {
+        TimeSeries {
+            labels: vec![Label {
+                name: LABEL_NAME.to_string(),
+                value: name.to_string(),
+            }],
+            samples: samples
+                .iter()
+                .map(|(timestamp, value)| Sample {
+                    value: *value,
+                    timestamp: *timestamp,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn frame(query_index: i64, series: &TimeSeries) -> Vec<u8> {
+        let message = prost::Message::encode_to_vec(&ChunkedReadResponse {
+            chunked_series: vec![ChunkedSeries {
+                labels: series.labels.clone(),
+                chunks: vec![Chunk {
+                    min_time_ms: series.samples.first().unwrap().timestamp,
+                    max_time_ms: series.samples.last().unwrap().timestamp,
+                    r#type: ChunkEncoding::Xor as i32,
+                    data: encode_xor_chunk(&series.samples),
+                }],
+            }],
+            query_index,
+        });
+        let mut frame = Vec::new();
+        prost::encoding::encode_varint(message.len() as u64, &mut frame);
+        frame.extend(crc32c(&message).to_be_bytes());
+        frame.extend(message);
+        frame
+    }
+
+    #[test]
+    pub fn computes_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    pub fn decodes_streamed_responses() {
+        let up = series("up", &[(1000, 1.0), (2000, 0.0)]);
+        let more_up = series("up", &[(3000, 1.0)]);
+        let other = series("requests", &[(1000, 5.0)]);
+        let mut body = frame(0, &up);
+        body.extend(frame(0, &more_up));
+        body.extend(frame(1, &other));
+        let response = ReadResponse::decode_stream(&body, 2).unwrap();
+        assert_eq!(response.results.len(), 2);
+        assert_eq!(
+            response.results[0].timeseries,
+            vec![series("up", &[(1000, 1.0), (2000, 0.0), (3000, 1.0)])]
+        );
+        assert_eq!(response.results[1].timeseries, vec![other]);
+
+        let mut corrupt = frame(0, &up);
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+        assert!(ReadResponse::decode_stream(&corrupt, 1).is_err());
+    }
+
+    #[test]
+    pub fn refuses_oversized_frame_lengths() {
+        for size in [u64::MAX, u64::MAX - 3, 1 << 40] {
+            let mut body = Vec::new();
+            prost::encoding::encode_varint(size, &mut body);
+            body.extend([0; 8]);
+            assert!(matches!(
+                ReadResponse::decode_stream(&body, 1),
+                Err(RemoteWriteError::Decode(DecodeError::InvalidChunk(
+                    "truncated frame"
+                )))
+            ));
+        }
+        let mut truncated = frame(0, &series("up", &[(1000, 1.0)]));
+        truncated.pop();
+        assert!(ReadResponse::decode_stream(&truncated, 1).is_err());
+    }
+
+    #[test]
+    pub fn refuses_unknown_query_indexes() {
+        for index in [-1, 1, 1 << 40] {
+            let body = frame(index, &series("up", &[(1000, 1.0)]));
+            assert!(matches!(
+                ReadResponse::decode_stream(&body, 1),
+                Err(RemoteWriteError::Decode(DecodeError::InvalidChunk(
+                    "query index out of range"
+                )))
+            ));
+        }
+    }
+
+    #[test]
+    pub fn refuses_overflowing_chunk_timestamps() {
+        // The encoder wraps, so these decode to deltas that overflow the timestamp.
+        for samples in [
+            &[(i64::MAX, 1.0), (i64::MIN, 1.0)][..],
+            &[(0, 1.0), (1, 1.0), (i64::MIN, 1.0)][..],
+        ] {
+            let body = frame(0, &series("up", samples));
+            assert!(matches!(
+                ReadResponse::decode_stream(&body, 1),
+                Err(RemoteWriteError::Decode(DecodeError::InvalidChunk(
+                    "timestamp out of range"
+                )))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    pub async fn reads_sampled_responses() {
+        let expected = ReadResponse {
+            results: vec![QueryResult {
+                timeseries: vec![series("up", &[(1000, 1.0)])],
+            }],
+        };
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&prost::Message::encode_to_vec(&expected))
+            .unwrap();
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header(
+                HEADER_NAME_REMOTE_READ_VERSION,
+                REMOTE_READ_VERSION_01,
+            ))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("content-type", CONTENT_TYPE)
+                    .set_body_bytes(body),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        let request = ReadRequest::new(vec![Query::new(
+            0,
+            2000,
+            vec![LabelMatcher::metric_name("up")],
+        )]);
+        let response = request
+            .send(&Client::new(), &server.uri(), "test")
+            .await
+            .unwrap();
+        assert_eq!(response, expected);
+    }
+}
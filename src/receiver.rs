@@ -0,0 +1,325 @@
+//! An embeddable remote write receiver.
+//!
+//! [`Receiver`] validates and decodes POSTed remote write requests and hands them
+//! to a [`WriteSink`]. It is not tied to an http server; call [`Receiver::handle`]
+//! from whichever framework you use and turn the [`ReceiverResponse`] into its
+//! response type.
+
+use std::fmt;
+
+use reqwest::{
+    header::{HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_TYPE as CONTENT_TYPE_HEADER},
+    Method, StatusCode,
+};
+
+use crate::{
+    decode::decompress, v2, DecodeError, ProtocolVersion, RemoteWriteError, WriteRequest,
+    WriteStats, DEFAULT_MAX_DECOMPRESSED_SIZE, HEADER_NAME_EXEMPLARS_WRITTEN,
+    HEADER_NAME_HISTOGRAMS_WRITTEN, HEADER_NAME_REMOTE_WRITE_VERSION, HEADER_NAME_SAMPLES_WRITTEN,
+};
+
+/// Why a sink could not take a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SinkError {
+    /// The sink is overloaded; the sender should back off and retry. Answered with 429.
+    Overloaded(String),
+    /// The request is invalid and must not be retried. Answered with 400.
+    Invalid(String),
+    /// The sink failed; the sender may retry. Answered with 500.
+    Internal(String),
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkError::Overloaded(msg) => write!(f, "overloaded: {msg}"),
+            SinkError::Invalid(msg) => write!(f, "invalid request: {msg}"),
+            SinkError::Internal(msg) => write!(f, "internal error: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for SinkError {}
+
+/// Destination of received write requests.
+pub trait WriteSink: Send + Sync {
+    /// Take a decoded request. `version` is the protocol version it was sent with;
+    /// 2.0 requests are converted to [`WriteRequest`] before they get here.
+    fn write(&self, version: ProtocolVersion, request: WriteRequest) -> Result<(), SinkError>;
+}
+
+impl<F> WriteSink for F
+where
+    F: Fn(ProtocolVersion, WriteRequest) -> Result<(), SinkError> + Send + Sync,
+{
+    fn write(&self, version: ProtocolVersion, request: WriteRequest) -> Result<(), SinkError> {
+        self(version, request)
+    }
+}
+
+/// Status, headers and body to answer a remote write request with.
+#[derive(Debug, Clone)]
+pub struct ReceiverResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+impl ReceiverResponse {
+    fn error(status: StatusCode, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            headers: HeaderMap::new(),
+            body: body.into(),
+        }
+    }
+
+    fn written(version: ProtocolVersion, written: WriteStats) -> Self {
+        let mut headers = HeaderMap::new();
+        if version == ProtocolVersion::V2 {
+            for (name, count) in [
+                (HEADER_NAME_SAMPLES_WRITTEN, written.samples),
+                (HEADER_NAME_HISTOGRAMS_WRITTEN, written.histograms),
+                (HEADER_NAME_EXEMPLARS_WRITTEN, written.exemplars),
+            ] {
+                headers.insert(name, HeaderValue::from(count));
+            }
+        }
+        Self {
+            status: StatusCode::NO_CONTENT,
+            headers,
+            body: String::new(),
+        }
+    }
+}
+
+/// Validates, decodes and forwards remote write requests to a sink.
+#[derive(Debug, Clone)]
+pub struct Receiver<S> {
+    sink: S,
+    max_decompressed_size: usize,
+}
+
+impl<S: WriteSink> Receiver<S> {
+    pub fn new(sink: S) -> Self {
+        Self {
+            sink,
+            max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+        }
+    }
+
+    /// Refuse requests that decompress to more than `max_decompressed_size` bytes.
+    pub fn with_max_decompressed_size(mut self, max_decompressed_size: usize) -> Self {
+        self.max_decompressed_size = max_decompressed_size;
+        self
+    }
+
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    /// Handle a single request.
+    ///
+    /// Answers 204 when the sink took the request, 400 for requests that cannot be
+    /// decoded (413 if they are too large to decode), 415 for unsupported encodings
+    /// and content types, and 429 or 500 when the sink is overloaded or fails.
+    pub fn handle(&self, method: &Method, headers: &HeaderMap, body: &[u8]) -> ReceiverResponse {
+        if method != Method::POST {
+            return ReceiverResponse::error(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "remote write requests must be POSTed",
+            );
+        }
+        let encoding = headers
+            .get(CONTENT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        if !encoding.eq_ignore_ascii_case("snappy") {
+            return ReceiverResponse::error(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("unsupported content encoding {encoding:?}, expected snappy"),
+            );
+        }
+        let version = match protocol_version(headers) {
+            Ok(version) => version,
+            Err((status, body)) => return ReceiverResponse::error(status, body),
+        };
+        let decoded = decompress(body, self.max_decompressed_size)
+            .map_err(RemoteWriteError::from)
+            .and_then(|buf| match version {
+                ProtocolVersion::V1 => WriteRequest::decode_proto3(&buf),
+                ProtocolVersion::V2 => v2::Request::decode_proto3(&buf).map(WriteRequest::from),
+            });
+        let request = match decoded {
+            Ok(request) => request,
+            Err(e @ RemoteWriteError::Decode(DecodeError::TooLarge { .. })) => {
+                return ReceiverResponse::error(StatusCode::PAYLOAD_TOO_LARGE, e.to_string())
+            }
+            Err(e) => return ReceiverResponse::error(StatusCode::BAD_REQUEST, e.to_string()),
+        };
+        let written = request.stats();
+        match self.sink.write(version, request) {
+            Ok(()) => ReceiverResponse::written(version, written),
+            Err(e @ SinkError::Overloaded(_)) => {
+                ReceiverResponse::error(StatusCode::TOO_MANY_REQUESTS, e.to_string())
+            }
+            Err(e @ SinkError::Invalid(_)) => {
+                ReceiverResponse::error(StatusCode::BAD_REQUEST, e.to_string())
+            }
+            Err(e @ SinkError::Internal(_)) => {
+                ReceiverResponse::error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+            }
+        }
+    }
+}
+
+/// Work out the protocol version from the content type and version headers.
+///
+/// A missing content type means 1.0, as the 2.0 specification allows.
+fn protocol_version(headers: &HeaderMap) -> Result<ProtocolVersion, (StatusCode, String)> {
+    let content_type = headers
+        .get(CONTENT_TYPE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("application/x-protobuf");
+    let mut parts = content_type.split(';').map(str::trim);
+    let media_type = parts.next().unwrap_or("");
+    let proto = parts
+        .find_map(|p| p.strip_prefix("proto="))
+        .unwrap_or("prometheus.WriteRequest");
+    let version = match (
+        media_type.eq_ignore_ascii_case("application/x-protobuf"),
+        proto,
+    ) {
+        (true, "prometheus.WriteRequest") => ProtocolVersion::V1,
+        (true, "io.prometheus.write.v2.Request") => ProtocolVersion::V2,
+        _ => {
+            return Err((
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("unsupported content type {content_type:?}"),
+            ))
+        }
+    };
+    let expected_prefix = match version {
+        ProtocolVersion::V1 => "0.1.",
+        ProtocolVersion::V2 => "2.0.",
+    };
+    match headers
+        .get(HEADER_NAME_REMOTE_WRITE_VERSION)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(v) if !v.starts_with(expected_prefix) => Err((
+            StatusCode::BAD_REQUEST,
+            format!("remote write version {v:?} does not match content type {content_type:?}"),
+        )),
+        _ => Ok(version),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::write_request;
+    use crate::{CONTENT_TYPE, CONTENT_TYPE_V2, REMOTE_WRITE_VERSION_01, REMOTE_WRITE_VERSION_20};
+    use pretty_assertions::assert_eq;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<(ProtocolVersion, WriteRequest)>>);
+
+    impl WriteSink for Recorder {
+        fn write(&self, version: ProtocolVersion, request: WriteRequest) -> Result<(), SinkError> {
+            self.0.lock().unwrap().push((version, request));
+            Ok(())
+        }
+    }
+
+    fn headers(content_type: &str, version: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("snappy"));
+        headers.insert(CONTENT_TYPE_HEADER, content_type.parse().unwrap());
+        headers.insert(HEADER_NAME_REMOTE_WRITE_VERSION, version.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    pub fn accepts_v1_and_v2_requests() {
+        let receiver = Receiver::new(Recorder::default());
+        let body = write_request().encode_compressed().unwrap();
+        let response = receiver.handle(
+            &Method::POST,
+            &headers(CONTENT_TYPE, REMOTE_WRITE_VERSION_01),
+            &body,
+        );
+        assert_eq!(response.status, StatusCode::NO_CONTENT);
+        assert!(response.headers.is_empty());
+
+        let body = v2::Request::from(write_request())
+            .encode_compressed()
+            .unwrap();
+        let response = receiver.handle(
+            &Method::POST,
+            &headers(CONTENT_TYPE_V2, REMOTE_WRITE_VERSION_20),
+            &body,
+        );
+        assert_eq!(response.status, StatusCode::NO_CONTENT);
+        assert_eq!(response.headers[HEADER_NAME_SAMPLES_WRITTEN], "1");
+
+        let received = receiver.sink().0.lock().unwrap();
+        assert_eq!(
+            received.iter().map(|(v, _)| *v).collect::<Vec<_>>(),
+            vec![ProtocolVersion::V1, ProtocolVersion::V2]
+        );
+        assert_eq!(received[1].1, write_request());
+    }
+
+    #[test]
+    pub fn rejects_invalid_requests() {
+        let receiver = Receiver::new(Recorder::default());
+        let body = write_request().encode_compressed().unwrap();
+        let mut uncompressed = headers(CONTENT_TYPE, REMOTE_WRITE_VERSION_01);
+        uncompressed.remove(CONTENT_ENCODING);
+        let cases = [
+            (
+                uncompressed,
+                body.clone(),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ),
+            (
+                headers("application/json", REMOTE_WRITE_VERSION_01),
+                body.clone(),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ),
+            (
+                headers(CONTENT_TYPE, REMOTE_WRITE_VERSION_20),
+                body.clone(),
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                headers(CONTENT_TYPE, REMOTE_WRITE_VERSION_01),
+                b"not snappy".to_vec(),
+                StatusCode::BAD_REQUEST,
+            ),
+        ];
+        for (headers, body, status) in cases {
+            assert_eq!(
+                receiver.handle(&Method::POST, &headers, &body).status,
+                status
+            );
+        }
+        assert!(receiver.sink().0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn answers_too_many_requests_when_sink_is_overloaded() {
+        let receiver = Receiver::new(|_: ProtocolVersion, _: WriteRequest| {
+            Err(SinkError::Overloaded("queue full".into()))
+        });
+        let body = write_request().encode_compressed().unwrap();
+        let response = receiver.handle(
+            &Method::POST,
+            &headers(CONTENT_TYPE, REMOTE_WRITE_VERSION_01),
+            &body,
+        );
+        assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+    }
+}
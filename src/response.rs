@@ -0,0 +1,175 @@
+//! Typed view of a remote write response.
+//!
+//! Remote Write 2.0 receivers report how much of a request they actually wrote
+//! in the `X-Prometheus-Remote-Write-*-Written` response headers. Comparing those
+//! against [`WriteRequest::stats`](crate::WriteRequest::stats) detects partial writes.
+
+use std::time::{Duration, SystemTime};
+
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    StatusCode,
+};
+
+use crate::{
+    retry::parse_retry_after, ProtocolVersion, RemoteWriteError, HEADER_NAME_EXEMPLARS_WRITTEN,
+    HEADER_NAME_HISTOGRAMS_WRITTEN, HEADER_NAME_SAMPLES_WRITTEN,
+};
+
+/// Number of samples, histograms and exemplars in a request, or written by a receiver.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteStats {
+    pub samples: u64,
+    pub histograms: u64,
+    pub exemplars: u64,
+}
+
+impl WriteStats {
+    /// Parse the written counts from response headers.
+    ///
+    /// Returns `None` when the receiver sent none of the headers, which is what a 1.0
+    /// receiver does. Headers that are missing or unparsable among present ones count as 0.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let names = [
+            HEADER_NAME_SAMPLES_WRITTEN,
+            HEADER_NAME_HISTOGRAMS_WRITTEN,
+            HEADER_NAME_EXEMPLARS_WRITTEN,
+        ];
+        if !names.iter().any(|name| headers.contains_key(*name)) {
+            return None;
+        }
+        let count = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .unwrap_or(0)
+        };
+        Some(Self {
+            samples: count(HEADER_NAME_SAMPLES_WRITTEN),
+            histograms: count(HEADER_NAME_HISTOGRAMS_WRITTEN),
+            exemplars: count(HEADER_NAME_EXEMPLARS_WRITTEN),
+        })
+    }
+
+    /// Add up the samples, histograms and exemplars of every series.
+    pub(crate) fn count(series: impl Iterator<Item = (usize, usize, usize)>) -> Self {
+        series.fold(
+            Self::default(),
+            |stats, (samples, histograms, exemplars)| Self {
+                samples: stats.samples + samples as u64,
+                histograms: stats.histograms + histograms as u64,
+                exemplars: stats.exemplars + exemplars as u64,
+            },
+        )
+    }
+
+    /// True if any count in `self` is lower than in `expected`.
+    pub fn is_less_than(&self, expected: &WriteStats) -> bool {
+        self.samples < expected.samples
+            || self.histograms < expected.histograms
+            || self.exemplars < expected.exemplars
+    }
+}
+
+/// The outcome of sending a write request.
+#[derive(Debug, Clone)]
+pub struct WriteResponse {
+    /// The protocol version the request that produced this response was encoded with.
+    pub version: ProtocolVersion,
+    pub status: StatusCode,
+    /// What the receiver reports to have written, if it reports it at all.
+    pub written: Option<WriteStats>,
+    /// The response body, only read for unsuccessful responses.
+    pub body: Option<String>,
+    /// How long the receiver asks to wait before sending again, from `Retry-After`.
+    pub retry_after: Option<Duration>,
+}
+
+impl WriteResponse {
+    /// Read status, written stats and, on failure, the body from `response`.
+    pub async fn read(
+        version: ProtocolVersion,
+        response: reqwest::Response,
+    ) -> Result<Self, RemoteWriteError> {
+        let status = response.status();
+        let written = WriteStats::from_headers(response.headers());
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| parse_retry_after(v, SystemTime::now()));
+        let body = if status.is_success() {
+            None
+        } else {
+            Some(response.text().await?)
+        };
+        Ok(Self {
+            version,
+            status,
+            written,
+            body,
+            retry_after,
+        })
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status.is_success()
+    }
+
+    /// True if the receiver accepted the request but reports writing less than `expected`.
+    ///
+    /// Receivers that do not report written stats are assumed to have written everything.
+    pub fn is_partial_write(&self, expected: &WriteStats) -> bool {
+        self.is_success()
+            && self
+                .written
+                .map(|written| written.is_less_than(expected))
+                .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    pub fn missing_headers_are_not_reported() {
+        assert_eq!(WriteStats::from_headers(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    pub fn parses_written_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(HEADER_NAME_SAMPLES_WRITTEN, HeaderValue::from_static("3"));
+        headers.insert(HEADER_NAME_EXEMPLARS_WRITTEN, HeaderValue::from_static("1"));
+        let written = WriteStats::from_headers(&headers).unwrap();
+        assert_eq!(
+            written,
+            WriteStats {
+                samples: 3,
+                histograms: 0,
+                exemplars: 1,
+            }
+        );
+        let response = WriteResponse {
+            version: ProtocolVersion::V2,
+            status: StatusCode::NO_CONTENT,
+            written: Some(written),
+            body: None,
+            retry_after: None,
+        };
+        assert!(response.is_partial_write(&WriteStats {
+            samples: 4,
+            histograms: 0,
+            exemplars: 1,
+        }));
+        assert!(!response.is_partial_write(&WriteStats {
+            samples: 3,
+            histograms: 0,
+            exemplars: 0,
+        }));
+    }
+}
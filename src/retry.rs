@@ -0,0 +1,230 @@
+//! Retrying failed sends.
+//!
+//! The specification asks senders to retry 5xx responses, and allows retrying 429,
+//! with backoff. [`RetryPolicy`] doubles the delay after every attempt, between a
+//! minimum and a maximum like Prometheus' `min_backoff` and `max_backoff`, and waits
+//! for as long as a receiver asks with `Retry-After` instead, up to a limit.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::{Duration, SystemTime},
+};
+
+/// When and how often to retry a send.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Delay before the first retry.
+    pub min_backoff: Duration,
+    /// Upper bound on the delay between attempts, unless the receiver asks for more with `Retry-After`.
+    pub max_backoff: Duration,
+    /// Fraction of each delay, between 0 and 1, randomly taken off so senders don't retry in lockstep.
+    pub jitter: f64,
+    /// Attempts in total, including the first one.
+    pub max_attempts: u32,
+    /// Upper bound on the delay a receiver can ask for with `Retry-After`.
+    pub max_retry_after: Duration,
+    /// Give up once retrying would take longer than this since the first attempt.
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    /// Retry up to 10 times, with Prometheus' default backoff of 30ms up to 5s,
+    /// waiting at most a minute when asked to with `Retry-After`.
+    fn default() -> Self {
+        Self {
+            min_backoff: Duration::from_millis(30),
+            max_backoff: Duration::from_secs(5),
+            jitter: 0.2,
+            max_attempts: 10,
+            max_retry_after: Duration::from_secs(60),
+            deadline: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send only once.
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_min_backoff(mut self, min_backoff: Duration) -> Self {
+        self.min_backoff = min_backoff;
+        self
+    }
+
+    pub fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn with_max_retry_after(mut self, max_retry_after: Duration) -> Self {
+        self.max_retry_after = max_retry_after;
+        self
+    }
+
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// The delay before retry number `retry`, counting from 1, without jitter.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.min_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+
+    /// The delay before retry number `retry`, with jitter.
+    pub(crate) fn jittered_backoff(&self, retry: u32) -> Duration {
+        jittered(self.backoff(retry), self.jitter)
+    }
+}
+
+/// `duration` shortened by a random fraction of up to `jitter`.
+///
+/// `jitter` is clamped to `[0, 1]`, and NaN counts as 0, since the fields holding it are public.
+pub(crate) fn jittered(duration: Duration, jitter: f64) -> Duration {
+    let jitter = if jitter.is_nan() {
+        0.0
+    } else {
+        jitter.clamp(0.0, 1.0)
+    };
+    // Rounding can make the product of a huge duration overflow; it never grows, so keep it.
+    Duration::try_from_secs_f64(duration.as_secs_f64() * (1.0 - jitter * random_fraction()))
+        .unwrap_or(duration)
+}
+
+/// A random number in `[0, 1)`, good enough to spread out retries.
+pub(crate) fn random_fraction() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Parse a `Retry-After` header: either a number of seconds or an http date.
+pub(crate) fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let at = parse_http_date(value)?;
+    Some(at.duration_since(now).unwrap_or_default())
+}
+
+/// Parse an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let (_, date) = value.split_once(", ")?;
+    let mut parts = date.split(' ');
+    let day: u64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ]
+    .iter()
+    .position(|m| *m == month)? as u64
+        + 1;
+    let year: u64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':').map(|p| p.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    // The header comes from the receiver, so check every field before doing arithmetic with it.
+    let in_range = (1970..=9999).contains(&year)
+        && (1..=31).contains(&day)
+        && hour < 24
+        && minute < 60
+        && second <= 60;
+    if parts.next()? != "GMT" || !in_range {
+        return None;
+    }
+    // Days since the epoch of a proleptic Gregorian date, shifting the year to start in March.
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era_days = y
+        .checked_mul(365)?
+        .checked_add(y / 4 - y / 100 + y / 400)?
+        .checked_add((153 * m + 2) / 5 + day - 1)?;
+    let days = era_days.checked_sub(719_468)?;
+    let seconds = days
+        .checked_mul(86_400)?
+        .checked_add(hour * 3_600 + minute * 60 + second)?;
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    pub fn backs_off_exponentially_up_to_the_maximum() {
+        let policy = RetryPolicy::new()
+            .with_min_backoff(Duration::from_millis(100))
+            .with_max_backoff(Duration::from_secs(1));
+        let delays = (1..=6)
+            .map(|retry| policy.backoff(retry))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis)
+        );
+        let jittered = policy.with_jitter(0.5).jittered_backoff(1);
+        assert!(jittered > Duration::from_millis(50) && jittered <= Duration::from_millis(100));
+        for jitter in [1.5, -1.0, f64::NAN, f64::INFINITY] {
+            let policy = RetryPolicy {
+                jitter,
+                ..RetryPolicy::default()
+            };
+            assert!(policy.jittered_backoff(1) <= policy.min_backoff);
+        }
+    }
+
+    #[test]
+    pub fn parses_retry_after() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_770);
+        assert_eq!(parse_retry_after("7", now), Some(Duration::from_secs(7)));
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT", now),
+            Some(Duration::from_secs(7))
+        );
+        assert_eq!(
+            parse_retry_after(
+                "Sun, 06 Nov 1994 08:49:37 GMT",
+                now + Duration::from_secs(60)
+            ),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+        for invalid in [
+            "Sun, 06 Nov 18446744073709551615 08:49:37 GMT",
+            "Sun, 06 Nov 99999 08:49:37 GMT",
+            "Sun, 32 Nov 1994 08:49:37 GMT",
+            "Sun, 00 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:49:37 GMT",
+            "Sun, 06 Nov 1994 08:60:37 GMT",
+            "Sun, 06 Nov 1994 08:49:61 GMT",
+        ] {
+            assert_eq!(parse_retry_after(invalid, now), None, "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Staleness markers for series that stop being reported.
+//!
+//! Prometheus marks a series stale as soon as a scrape no longer returns it.
+//! A series that simply stops being pushed, e.g. because its child was removed
+//! from a `CounterVec`, stays visible to queries for the 5 minute lookback instead.
+//! [`StalenessTracker`] remembers the series of the last request written and adds
+//! a [`STALE_NAN`](crate::STALE_NAN) sample for every series that has vanished since.
+
+use std::collections::HashSet;
+
+use crate::{Label, WriteRequest};
+
+/// Remembers the series pushed last time, to mark the ones that vanish as stale.
+#[derive(Debug, Clone)]
+pub struct StalenessTracker {
+    /// Series of the last request written.
+    live: HashSet<Vec<Label>>,
+    /// Series of the last request tracked, until it is known to be written.
+    pending: Option<HashSet<Vec<Label>>>,
+    /// The latest timestamp seen, so stale markers never come before a sample.
+    last_timestamp: i64,
+}
+
+impl Default for StalenessTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StalenessTracker {
+    pub fn new() -> Self {
+        Self {
+            live: HashSet::new(),
+            pending: None,
+            last_timestamp: i64::MIN,
+        }
+    }
+
+    /// Add a stale marker at `now` to `request` for every series of the last request
+    /// written that it no longer contains.
+    ///
+    /// Call it on every request about to be sent, before sending, and call
+    /// [`commit`](Self::commit) once it was written. Until then, the series of the
+    /// last request written stay live, so if the send fails, the next request gets
+    /// the stale markers again.
+    pub fn track(&mut self, request: &mut WriteRequest, now: i64) {
+        let live = request
+            .timeseries
+            .iter()
+            .map(|ts| ts.labels.clone())
+            .collect::<HashSet<_>>();
+        let last_timestamp = request
+            .timeseries
+            .iter()
+            .flat_map(|ts| {
+                let samples = ts.samples.iter().map(|s| s.timestamp);
+                samples.chain(ts.histograms.iter().map(|h| h.timestamp))
+            })
+            .max()
+            .unwrap_or(i64::MIN);
+        let vanished = self
+            .live
+            .iter()
+            .filter(|labels| !live.contains(*labels))
+            .cloned()
+            .collect::<Vec<_>>();
+        self.pending = Some(live);
+        if !vanished.is_empty() {
+            let timestamp = self.stale_timestamp(now);
+            request
+                .timeseries
+                .extend(WriteRequest::stale_markers(vanished, timestamp).timeseries);
+        }
+        self.last_timestamp = self.last_timestamp.max(last_timestamp);
+    }
+
+    /// Remember the series of the request last tracked as live, now that it was written.
+    pub fn commit(&mut self) {
+        if let Some(live) = self.pending.take() {
+            self.live = live;
+        }
+    }
+
+    /// A request marking every series of the last request written as stale at `now`,
+    /// e.g. on shutdown. The series are forgotten.
+    pub fn stale_markers(&mut self, now: i64) -> WriteRequest {
+        self.pending = None;
+        let timestamp = self.stale_timestamp(now);
+        WriteRequest::stale_markers(self.live.drain(), timestamp)
+    }
+
+    /// Number of series of the last request written.
+    pub fn len(&self) -> usize {
+        self.live.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live.is_empty()
+    }
+
+    fn stale_timestamp(&mut self, now: i64) -> i64 {
+        self.last_timestamp = now.max(self.last_timestamp.saturating_add(1));
+        self.last_timestamp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConversionOptions, FixedClock, LABEL_NAME};
+    use pretty_assertions::assert_eq;
+    use prometheus::{CounterVec, Opts, Registry};
+
+    #[test]
+    pub fn marks_vanished_series_as_stale() {
+        let registry = Registry::new();
+        let counter = CounterVec::new(Opts::new("requests_total", "requests"), &["path"]).unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.with_label_values(&["/a"]).inc();
+        counter.with_label_values(&["/b"]).inc();
+        let mut tracker = StalenessTracker::new();
+        let request = |now| {
+            WriteRequest::from_metric_families_with_options(
+                &registry.gather(),
+                &ConversionOptions::new().with_clock(FixedClock(now)),
+            )
+            .unwrap()
+        };
+
+        let mut first = request(1_000);
+        tracker.track(&mut first, 1_000);
+        tracker.commit();
+        assert_eq!(first.timeseries.len(), 2);
+        assert_eq!(tracker.len(), 2);
+
+        counter.remove_label_values(&["/b"]).unwrap();
+        let mut second = request(2_000);
+        tracker.track(&mut second, 2_000);
+        tracker.commit();
+        let stale = second
+            .timeseries
+            .iter()
+            .filter(|ts| ts.samples[0].is_stale())
+            .collect::<Vec<_>>();
+        assert_eq!(second.timeseries.len(), 2);
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].label(LABEL_NAME), Some("requests_total"));
+        assert_eq!(stale[0].label("path"), Some("/b"));
+        assert_eq!(stale[0].samples[0].timestamp, 2_000);
+        assert_eq!(tracker.len(), 1);
+
+        let mut third = request(3_000);
+        tracker.track(&mut third, 3_000);
+        tracker.commit();
+        assert_eq!(third.timeseries.len(), 1);
+
+        let shutdown = tracker.stale_markers(3_000);
+        assert_eq!(shutdown.timeseries.len(), 1);
+        assert_eq!(shutdown.timeseries[0].label("path"), Some("/a"));
+        assert_eq!(shutdown.timeseries[0].samples[0].timestamp, 3_001);
+        assert!(tracker.is_empty());
+    }
+
+    #[test]
+    pub fn marks_again_until_written() {
+        let series = |path: &str| crate::TimeSeries {
+            labels: vec![Label {
+                name: "path".to_string(),
+                value: path.to_string(),
+            }],
+            samples: vec![crate::Sample {
+                value: 1.0,
+                timestamp: 1_000,
+            }],
+            ..Default::default()
+        };
+        let request = |paths: &[&str]| WriteRequest {
+            timeseries: paths.iter().map(|p| series(p)).collect(),
+            metadata: vec![],
+        };
+        let stale = |request: &WriteRequest| {
+            request
+                .timeseries
+                .iter()
+                .filter(|ts| ts.samples[0].is_stale())
+                .map(|ts| ts.label("path").unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        let mut tracker = StalenessTracker::new();
+        let mut first = request(&["/a", "/b"]);
+        tracker.track(&mut first, 1_000);
+        tracker.commit();
+
+        // Not written, e.g. because the receiver answered 503.
+        let mut failed = request(&["/a"]);
+        tracker.track(&mut failed, 2_000);
+        assert_eq!(stale(&failed), vec!["/b"]);
+
+        let mut retried = request(&["/a"]);
+        tracker.track(&mut retried, 3_000);
+        assert_eq!(stale(&retried), vec!["/b"]);
+        tracker.commit();
+
+        let mut next = request(&["/a"]);
+        tracker.track(&mut next, 4_000);
+        assert!(stale(&next).is_empty());
+    }
+}
@@ -0,0 +1,611 @@
+//! Prometheus Remote Write 2.0.
+//!
+//! Models the `io.prometheus.write.v2.Request` message. Instead of repeating label
+//! strings for every series, a 2.0 request carries a single table of interned
+//! symbols and every series refers to its labels by index into that table.
+//! See https://prometheus.io/docs/specs/remote_write_spec_2_0/.
+
+use std::collections::{HashMap, HashSet};
+
+use prometheus::proto::MetricFamily;
+use reqwest::Client;
+
+use crate::{
+    decode::decompress, metadata::MetadataIndex, ConversionOptions, Label, MetadataType,
+    MetricMetadata, NativeHistogram, RemoteWriteError, Sample, WriteRequest, WriteStats,
+    CONTENT_TYPE_V2, COUNT_SUFFIX, CREATED_SUFFIX, DEFAULT_MAX_DECOMPRESSED_SIZE,
+    HEADER_NAME_REMOTE_WRITE_VERSION, LABEL_NAME, REMOTE_WRITE_VERSION_20, SUM_SUFFIX,
+    TOTAL_SUFFIX,
+};
+
+/// The type of a metric, as carried in series metadata.
+///
+/// Numbered the same as the 1.0 [`MetadataType`](crate::MetadataType).
+///
+/// .proto:
+/// ```protobuf
+/// enum MetricType {
+///   METRIC_TYPE_UNSPECIFIED    = 0;
+///   METRIC_TYPE_COUNTER        = 1;
+///   METRIC_TYPE_GAUGE          = 2;
+///   METRIC_TYPE_HISTOGRAM      = 3;
+///   METRIC_TYPE_GAUGEHISTOGRAM = 4;
+///   METRIC_TYPE_SUMMARY        = 5;
+///   METRIC_TYPE_INFO           = 6;
+///   METRIC_TYPE_STATESET       = 7;
+/// }
+/// ```
+#[derive(prost::Enumeration, Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(i32)]
+pub enum MetricType {
+    Unspecified = 0,
+    Counter = 1,
+    Gauge = 2,
+    Histogram = 3,
+    GaugeHistogram = 4,
+    Summary = 5,
+    Info = 6,
+    Stateset = 7,
+}
+
+/// Metadata attached to a single series.
+///
+/// .proto:
+/// ```protobuf
+/// message Metadata {
+///   MetricType type = 1;
+///   uint32 help_ref = 3;
+///   uint32 unit_ref = 4;
+/// }
+/// ```
+#[derive(prost::Message, Clone, PartialEq)]
+pub struct Metadata {
+    #[prost(enumeration = "MetricType", tag = "1")]
+    pub r#type: i32,
+    #[prost(uint32, tag = "3")]
+    pub help_ref: u32,
+    #[prost(uint32, tag = "4")]
+    pub unit_ref: u32,
+}
+
+/// An exemplar.
+///
+/// .proto:
+/// ```protobuf
+/// message Exemplar {
+///   repeated uint32 labels_refs = 1;
+///   double value                = 2;
+///   int64 timestamp             = 3;
+/// }
+/// ```
+#[derive(prost::Message, Clone, PartialEq)]
+pub struct Exemplar {
+    #[prost(uint32, repeated, tag = "1")]
+    pub labels_refs: Vec<u32>,
+    #[prost(double, tag = "2")]
+    pub value: f64,
+    #[prost(int64, tag = "3")]
+    pub timestamp: i64,
+}
+
+/// A time series.
+///
+/// .proto:
+/// ```protobuf
+/// message TimeSeries {
+///   repeated uint32 labels_refs = 1;
+///   repeated Sample samples     = 2;
+///   repeated Histogram histograms = 3;
+///   repeated Exemplar exemplars = 4;
+///   Metadata metadata           = 5;
+///   int64 created_timestamp     = 6;
+/// }
+/// ```
+#[derive(prost::Message, Clone, PartialEq)]
+pub struct TimeSeries {
+    /// Pairs of `(name_ref, value_ref)` into [`Request::symbols`], sorted by label name.
+    #[prost(uint32, repeated, tag = "1")]
+    pub labels_refs: Vec<u32>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+    #[prost(message, repeated, tag = "3")]
+    pub histograms: Vec<NativeHistogram>,
+    #[prost(message, repeated, tag = "4")]
+    pub exemplars: Vec<Exemplar>,
+    #[prost(message, optional, tag = "5")]
+    pub metadata: Option<Metadata>,
+    /// When the counter, summary or histogram started counting, in milliseconds. 0 if unknown.
+    #[prost(int64, tag = "6")]
+    pub created_timestamp: i64,
+}
+
+/// A Remote Write 2.0 request.
+///
+/// .proto:
+/// ```protobuf
+/// message Request {
+///   // Fields 1 to 3 were used by the 1.0 WriteRequest.
+///   reserved 1 to 3;
+///
+///   repeated string symbols        = 4;
+///   repeated TimeSeries timeseries = 5;
+/// }
+/// ```
+#[derive(prost::Message, Clone, PartialEq)]
+pub struct Request {
+    /// Interned strings. The first entry is always the empty string.
+    #[prost(string, repeated, tag = "4")]
+    pub symbols: Vec<String>,
+    #[prost(message, repeated, tag = "5")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+/// Deduplicates strings into the symbols table of a [`Request`].
+#[derive(Debug, Clone)]
+pub struct SymbolTable {
+    symbols: Vec<String>,
+    index: HashMap<String, u32>,
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SymbolTable {
+    /// A table holding only the mandatory empty string at index 0.
+    pub fn new() -> Self {
+        let mut index = HashMap::new();
+        index.insert(String::new(), 0);
+        Self {
+            symbols: vec![String::new()],
+            index,
+        }
+    }
+
+    /// Get the reference for `symbol`, adding it to the table if it is new.
+    pub fn intern(&mut self, symbol: &str) -> u32 {
+        if let Some(r) = self.index.get(symbol) {
+            return *r;
+        }
+        let r = self.symbols.len() as u32;
+        self.symbols.push(symbol.to_string());
+        self.index.insert(symbol.to_string(), r);
+        r
+    }
+
+    pub fn symbols(&self) -> &[String] {
+        &self.symbols
+    }
+
+    pub fn into_symbols(self) -> Vec<String> {
+        self.symbols
+    }
+}
+
+impl Request {
+    /// Resolve a symbol reference. Unknown references resolve to the empty string.
+    pub fn symbol(&self, r: u32) -> &str {
+        self.symbols
+            .get(r as usize)
+            .map(String::as_str)
+            .unwrap_or("")
+    }
+
+    /// The labels of `series` as `(name, value)` pairs.
+    pub fn labels<'a>(
+        &'a self,
+        series: &'a TimeSeries,
+    ) -> impl Iterator<Item = (&'a str, &'a str)> {
+        series
+            .labels_refs
+            .chunks_exact(2)
+            .map(|pair| (self.symbol(pair[0]), self.symbol(pair[1])))
+    }
+
+    /// Sort the label references of every series by label name, and the samples by timestamp.
+    ///
+    /// Required by the specification.
+    pub fn sort(&mut self) {
+        let symbols = &self.symbols;
+        let name = |r: u32| symbols.get(r as usize).map(String::as_str).unwrap_or("");
+        for series in &mut self.timeseries {
+            let mut pairs = series
+                .labels_refs
+                .chunks_exact(2)
+                .map(|pair| (pair[0], pair[1]))
+                .collect::<Vec<_>>();
+            pairs.sort_by(|a, b| name(a.0).cmp(name(b.0)));
+            series.labels_refs = pairs.into_iter().flat_map(|(n, v)| [n, v]).collect();
+            series.samples.sort_by_key(|s| s.timestamp);
+            series.histograms.sort_by_key(|h| h.timestamp);
+            series.exemplars.sort_by_key(|e| e.timestamp);
+        }
+    }
+
+    pub fn sorted(mut self) -> Self {
+        self.sort();
+        self
+    }
+
+    /// Count what this request contains, to compare against what a receiver reports as written.
+    pub fn stats(&self) -> WriteStats {
+        WriteStats::count(
+            self.timeseries
+                .iter()
+                .map(|ts| (ts.samples.len(), ts.histograms.len(), ts.exemplars.len())),
+        )
+    }
+
+    /// Encode this request as a protobuf message.
+    ///
+    /// NOTE: The API requires snappy compression, not a raw protobuf message.
+    pub fn encode_proto3(self) -> Vec<u8> {
+        prost::Message::encode_to_vec(&self.sorted())
+    }
+
+    pub fn encode_compressed(self) -> Result<Vec<u8>, RemoteWriteError> {
+        Ok(snap::raw::Encoder::new().compress_vec(&self.encode_proto3())?)
+    }
+
+    /// Decode a raw protobuf message.
+    pub fn decode_proto3(buf: &[u8]) -> Result<Self, RemoteWriteError> {
+        Ok(prost::Message::decode(buf)?)
+    }
+
+    /// Decode a snappy compressed payload, as sent over the wire.
+    ///
+    /// Payloads that decompress to more than [`DEFAULT_MAX_DECOMPRESSED_SIZE`] are refused.
+    pub fn decode_compressed(buf: &[u8]) -> Result<Self, RemoteWriteError> {
+        Self::decode_compressed_with_limit(buf, DEFAULT_MAX_DECOMPRESSED_SIZE)
+    }
+
+    /// Decode a snappy compressed payload that decompresses to at most `max_size` bytes.
+    pub fn decode_compressed_with_limit(
+        buf: &[u8],
+        max_size: usize,
+    ) -> Result<Self, RemoteWriteError> {
+        Self::decode_proto3(&decompress(buf, max_size)?)
+    }
+
+    /// Encode Prometheus metric families into a Remote Write 2.0 request.
+    ///
+    /// Produces the same series as [`WriteRequest::from_metric_families`], with the
+    /// type and help text of the metric family attached to every series.
+    pub fn from_metric_families(
+        metric_families: Vec<MetricFamily>,
+        custom_labels: Option<Vec<(String, String)>>,
+    ) -> Result<Self, RemoteWriteError> {
+        Ok(WriteRequest::from_metric_families(metric_families, custom_labels)?.into())
+    }
+
+    /// Encode Prometheus metric families into a Remote Write 2.0 request, as configured by `options`.
+    pub fn from_metric_families_with_options(
+        metric_families: &[MetricFamily],
+        options: &ConversionOptions,
+    ) -> Result<Self, RemoteWriteError> {
+        Ok(WriteRequest::from_metric_families_with_options(metric_families, options)?.into())
+    }
+
+    pub fn build_http_request(
+        self,
+        client: Client,
+        endpoint: &str,
+        user_agent: &str,
+    ) -> Result<reqwest::Request, RemoteWriteError> {
+        let body = self.encode_compressed()?;
+        client
+            .post(endpoint)
+            .header(reqwest::header::CONTENT_TYPE, CONTENT_TYPE_V2)
+            .header(HEADER_NAME_REMOTE_WRITE_VERSION, REMOTE_WRITE_VERSION_20)
+            .header(reqwest::header::CONTENT_ENCODING, "snappy")
+            .header(reqwest::header::USER_AGENT, user_agent)
+            .body(body)
+            .build()
+            .map_err(RemoteWriteError::HttpBuild)
+    }
+}
+
+/// Intern the labels of a v1 series, leaving metadata empty.
+fn interned_series(mut series: crate::TimeSeries, symbols: &mut SymbolTable) -> TimeSeries {
+    series.sort_labels_and_samples();
+    let labels_refs = series
+        .labels
+        .iter()
+        .flat_map(|l| [symbols.intern(&l.name), symbols.intern(&l.value)])
+        .collect();
+    let exemplars = series
+        .exemplars
+        .into_iter()
+        .map(|e| Exemplar {
+            labels_refs: e
+                .labels
+                .iter()
+                .flat_map(|l| [symbols.intern(&l.name), symbols.intern(&l.value)])
+                .collect(),
+            value: e.value,
+            timestamp: e.timestamp,
+        })
+        .collect();
+    TimeSeries {
+        labels_refs,
+        samples: series.samples,
+        histograms: series.histograms,
+        exemplars,
+        metadata: None,
+        created_timestamp: 0,
+    }
+}
+
+/// True if `name` is the `_created` series of the counter, summary or histogram `metadata` describes.
+fn is_created_series(metadata: &MetricMetadata, name: &str) -> bool {
+    let cumulative = [
+        MetadataType::Counter,
+        MetadataType::Summary,
+        MetadataType::Histogram,
+    ]
+    .map(|t| t as i32)
+    .contains(&metadata.r#type);
+    cumulative && name.ends_with(CREATED_SUFFIX) && name != metadata.metric_family_name
+}
+
+/// The family a `_created` series is matched to the other series of its child by:
+/// the metadata describing it, or without metadata, its name without the suffix.
+#[derive(Hash, PartialEq, Eq)]
+enum Family<'a> {
+    Metadata(usize),
+    Stem(&'a str),
+}
+
+/// `name` without the suffix the series of a counter, summary or histogram add.
+fn stem(name: &str) -> &str {
+    [
+        TOTAL_SUFFIX,
+        SUM_SUFFIX,
+        COUNT_SUFFIX,
+        "_bucket",
+        CREATED_SUFFIX,
+    ]
+    .into_iter()
+    .find_map(|suffix| name.strip_suffix(suffix))
+    .unwrap_or(name)
+}
+
+/// The labels telling apart the children of a metric family, i.e. all but the
+/// name and the `le` and `quantile` labels that the series of one child add.
+fn child_labels(series: &crate::TimeSeries) -> Vec<&Label> {
+    let mut labels = series
+        .labels
+        .iter()
+        .filter(|l| ![LABEL_NAME, "le", "quantile"].contains(&l.name.as_str()))
+        .collect::<Vec<_>>();
+    labels.sort_by(|a, b| a.name.cmp(&b.name));
+    labels
+}
+
+/// The child `series` belongs to, in the family with the metadata at `family`, if any.
+fn child(series: &crate::TimeSeries, family: Option<usize>) -> (Family<'_>, Vec<&Label>) {
+    let family = match family {
+        Some(i) => Family::Metadata(i),
+        None => Family::Stem(stem(metric_name(series))),
+    };
+    (family, child_labels(series))
+}
+
+fn metric_name(series: &crate::TimeSeries) -> &str {
+    series
+        .labels
+        .iter()
+        .find(|l| l.name == LABEL_NAME)
+        .map(|l| l.value.as_str())
+        .unwrap_or("")
+}
+
+impl From<WriteRequest> for Request {
+    /// Re-encode a v1 request as v2.
+    ///
+    /// Every series gets the metadata of the metric family it belongs to, if the v1
+    /// request carries it. 2.0 carries created timestamps on the series themselves,
+    /// so the `_created` series of counters, summaries and histograms are folded into
+    /// the `created_timestamp` of the other series of the same child. Without metadata,
+    /// e.g. on requests throttled by [`MetadataThrottle`](crate::MetadataThrottle), a
+    /// `_created` series is folded if the request has other series of the same child.
+    fn from(value: WriteRequest) -> Self {
+        let mut symbols = SymbolTable::new();
+        let metadata = value
+            .metadata
+            .iter()
+            .map(|m| {
+                let metadata = Metadata {
+                    r#type: m.r#type,
+                    help_ref: symbols.intern(&m.help),
+                    unit_ref: symbols.intern(&m.unit),
+                };
+                (m, metadata)
+            })
+            .collect::<Vec<_>>();
+        let index = MetadataIndex::new(&value.metadata);
+        let families = value
+            .timeseries
+            .iter()
+            .map(|series| index.find(metric_name(series)))
+            .collect::<Vec<_>>();
+        // The children without metadata that have series besides `_created`.
+        let children = value
+            .timeseries
+            .iter()
+            .zip(&families)
+            .filter(|(series, family)| {
+                family.is_none() && !metric_name(series).ends_with(CREATED_SUFFIX)
+            })
+            .map(|(series, family)| child(series, *family))
+            .collect::<HashSet<_>>();
+        // The created timestamp of every child, from its `_created` series, in seconds.
+        let mut created = HashMap::new();
+        let mut created_series = Vec::with_capacity(value.timeseries.len());
+        for (series, family) in value.timeseries.iter().zip(&families) {
+            let name = metric_name(series);
+            let key = child(series, *family);
+            let is_created = match family {
+                Some(i) => is_created_series(metadata[*i].0, name),
+                None => name.ends_with(CREATED_SUFFIX) && children.contains(&key),
+            };
+            if let (true, Some(sample)) = (is_created, series.samples.last()) {
+                created.insert(key, (sample.value * 1000.0).round() as i64);
+            }
+            created_series.push(is_created);
+        }
+        let created_timestamps = value
+            .timeseries
+            .iter()
+            .zip(&families)
+            .map(|(series, family)| match created.is_empty() {
+                false => created.get(&child(series, *family)).copied().unwrap_or(0),
+                true => 0,
+            })
+            .collect::<Vec<_>>();
+        let timeseries = value
+            .timeseries
+            .into_iter()
+            .zip(families.into_iter().zip(created_series))
+            .zip(created_timestamps)
+            .filter(|((_, (_, created_series)), _)| !created_series)
+            .map(|((series, (family, _)), created_timestamp)| TimeSeries {
+                metadata: family.map(|i| metadata[i].1.clone()),
+                created_timestamp,
+                ..interned_series(series, &mut symbols)
+            })
+            .collect();
+        Self {
+            symbols: symbols.into_symbols(),
+            timeseries,
+        }
+    }
+}
+
+impl From<Request> for WriteRequest {
+    /// Resolve the symbols of a v2 request into a v1 request.
+    ///
+    /// Series metadata becomes one [`MetricMetadata`] per distinct metric name.
+    fn from(value: Request) -> Self {
+        let resolve = |refs: &[u32]| {
+            refs.chunks_exact(2)
+                .map(|pair| Label {
+                    name: value.symbol(pair[0]).to_string(),
+                    value: value.symbol(pair[1]).to_string(),
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut metadata: Vec<MetricMetadata> = Vec::new();
+        let timeseries = value
+            .timeseries
+            .iter()
+            .map(|series| {
+                if let (Some(m), Some(name)) = (&series.metadata, series.metric_name(&value)) {
+                    if !metadata
+                        .iter()
+                        .any(|known| known.metric_family_name == name)
+                    {
+                        metadata.push(MetricMetadata {
+                            r#type: m.r#type,
+                            metric_family_name: name.to_string(),
+                            help: value.symbol(m.help_ref).to_string(),
+                            unit: value.symbol(m.unit_ref).to_string(),
+                        });
+                    }
+                }
+                crate::TimeSeries {
+                    labels: resolve(&series.labels_refs),
+                    samples: series.samples.clone(),
+                    exemplars: series
+                        .exemplars
+                        .iter()
+                        .map(|e| crate::Exemplar {
+                            labels: resolve(&e.labels_refs),
+                            value: e.value,
+                            timestamp: e.timestamp,
+                        })
+                        .collect(),
+                    histograms: series.histograms.clone(),
+                }
+            })
+            .collect();
+        Self {
+            timeseries,
+            metadata,
+        }
+    }
+}
+
+impl TimeSeries {
+    /// The metric name of this series, if it has one.
+    pub fn metric_name<'a>(&self, request: &'a Request) -> Option<&'a str> {
+        self.labels_refs
+            .chunks_exact(2)
+            .find(|pair| request.symbol(pair[0]) == LABEL_NAME)
+            .map(|pair| request.symbol(pair[1]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use prometheus::{Counter, CounterVec, Opts, Registry};
+
+    #[test]
+    pub fn deduplicates_label_strings_into_symbols() {
+        let registry = Registry::new();
+        let counter =
+            CounterVec::new(Opts::new("requests", "handled requests"), &["path"]).unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.with_label_values(&["/a"]).inc();
+        counter.with_label_values(&["/b"]).inc_by(2.0);
+        let req = Request::from_metric_families(registry.gather(), None)
+            .expect("Failed to encode counter");
+        assert_eq!(req.symbols.first().unwrap(), "");
+        assert_eq!(req.timeseries.len(), 2);
+        assert_eq!(req.symbols.iter().filter(|s| *s == "requests").count(), 1);
+        let labels = req.labels(&req.timeseries[1]).collect::<Vec<_>>();
+        assert_eq!(labels, vec![(LABEL_NAME, "requests"), ("path", "/b")]);
+        let metadata = req.timeseries[0].metadata.clone().unwrap();
+        assert_eq!(metadata.r#type, MetricType::Counter as i32);
+        assert_eq!(req.symbol(metadata.help_ref), "handled requests");
+    }
+
+    #[test]
+    pub fn roundtrips_through_protobuf() {
+        let registry = Registry::new();
+        let counter = Counter::new("my_counter", "an extra description").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.inc_by(5.0);
+        let req = Request::from_metric_families(registry.gather(), None).unwrap();
+        let decoded: Request =
+            prost::Message::decode(req.clone().encode_proto3().as_slice()).unwrap();
+        assert_eq!(decoded, req);
+        assert_eq!(
+            decoded.timeseries[0].metric_name(&decoded),
+            Some("my_counter")
+        );
+    }
+
+    #[test]
+    pub fn sets_v2_headers() {
+        let req = Request::from(WriteRequest {
+            timeseries: vec![],
+            metadata: vec![],
+        });
+        let http = req
+            .build_http_request(Client::new(), "http://localhost/api/v1/write", "test")
+            .unwrap();
+        assert_eq!(
+            http.headers()[reqwest::header::CONTENT_TYPE],
+            CONTENT_TYPE_V2
+        );
+        assert_eq!(
+            http.headers()[HEADER_NAME_REMOTE_WRITE_VERSION],
+            REMOTE_WRITE_VERSION_20
+        );
+    }
+}
@@ -0,0 +1,248 @@
+//! Checking write requests against the remote write specification.
+//!
+//! Receivers reject a whole batch when a single series breaks the rules, usually
+//! with a `400` that does not say which series. [`WriteRequest::validate`] reports
+//! every violation up front, with the index of the offending series.
+
+use std::{collections::HashMap, fmt};
+
+use crate::{Label, RemoteWriteError, TimeSeries, WriteRequest, LABEL_NAME};
+
+/// A way a series breaks the remote write specification.
+///
+/// `series` is the index of the series in [`WriteRequest::timeseries`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// The series has no `__name__` label, or it is empty.
+    MissingMetricName { series: usize },
+    /// A label name appears more than once.
+    DuplicateLabelName { series: usize, name: String },
+    /// A label has an empty value; receivers treat this as the label being absent.
+    EmptyLabelValue { series: usize, name: String },
+    /// The metric name does not match `[a-zA-Z_:][a-zA-Z0-9_:]*`.
+    InvalidMetricName { series: usize, name: String },
+    /// A label name does not match `[a-zA-Z_][a-zA-Z0-9_]*`.
+    InvalidLabelName { series: usize, name: String },
+    /// Labels are not sorted by name.
+    UnsortedLabels { series: usize },
+    /// Sample or histogram timestamps do not strictly increase.
+    NonIncreasingTimestamps { series: usize },
+    /// The series has the same labels as an earlier one.
+    DuplicateSeries { series: usize, first: usize },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::MissingMetricName { series } => {
+                write!(f, "series {series} has no metric name")
+            }
+            Violation::DuplicateLabelName { series, name } => {
+                write!(f, "series {series} has label {name:?} more than once")
+            }
+            Violation::EmptyLabelValue { series, name } => {
+                write!(f, "series {series} has an empty value for label {name:?}")
+            }
+            Violation::InvalidMetricName { series, name } => {
+                write!(f, "series {series} has invalid metric name {name:?}")
+            }
+            Violation::InvalidLabelName { series, name } => {
+                write!(f, "series {series} has invalid label name {name:?}")
+            }
+            Violation::UnsortedLabels { series } => {
+                write!(f, "series {series} has labels not sorted by name")
+            }
+            Violation::NonIncreasingTimestamps { series } => {
+                write!(f, "series {series} has timestamps that do not increase")
+            }
+            Violation::DuplicateSeries { series, first } => {
+                write!(f, "series {series} has the same labels as series {first}")
+            }
+        }
+    }
+}
+
+fn is_valid_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Check a single series, appending what is wrong with it to `violations`.
+fn validate_series(index: usize, series: &TimeSeries, violations: &mut Vec<Violation>) {
+    match series.label(LABEL_NAME) {
+        None | Some("") => violations.push(Violation::MissingMetricName { series: index }),
+        Some(name) if !is_valid_metric_name(name) => {
+            violations.push(Violation::InvalidMetricName {
+                series: index,
+                name: name.to_string(),
+            })
+        }
+        Some(_) => {}
+    }
+    for (i, label) in series.labels.iter().enumerate() {
+        if label.name != LABEL_NAME && !is_valid_label_name(&label.name) {
+            violations.push(Violation::InvalidLabelName {
+                series: index,
+                name: label.name.clone(),
+            });
+        }
+        if label.value.is_empty() && label.name != LABEL_NAME {
+            violations.push(Violation::EmptyLabelValue {
+                series: index,
+                name: label.name.clone(),
+            });
+        }
+        let first = series.labels.iter().position(|l| l.name == label.name);
+        let repeats = series.labels[i + 1..].iter().any(|l| l.name == label.name);
+        if first == Some(i) && repeats {
+            violations.push(Violation::DuplicateLabelName {
+                series: index,
+                name: label.name.clone(),
+            });
+        }
+    }
+    if series.labels.windows(2).any(|w| w[0].name > w[1].name) {
+        violations.push(Violation::UnsortedLabels { series: index });
+    }
+    let samples_increase = series
+        .samples
+        .windows(2)
+        .all(|w| w[0].timestamp < w[1].timestamp);
+    let histograms_increase = series
+        .histograms
+        .windows(2)
+        .all(|w| w[0].timestamp < w[1].timestamp);
+    if !samples_increase || !histograms_increase {
+        violations.push(Violation::NonIncreasingTimestamps { series: index });
+    }
+}
+
+impl WriteRequest {
+    /// Check this request against the remote write specification.
+    ///
+    /// Returns every violation found, or an empty list if the request is valid.
+    pub fn validate(&self) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let mut seen: HashMap<Vec<&Label>, usize> = HashMap::new();
+        for (index, series) in self.timeseries.iter().enumerate() {
+            validate_series(index, series, &mut violations);
+            let mut labels = series.labels.iter().collect::<Vec<_>>();
+            labels.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.value.cmp(&b.value)));
+            if let Some(first) = seen.get(&labels) {
+                violations.push(Violation::DuplicateSeries {
+                    series: index,
+                    first: *first,
+                });
+            } else {
+                seen.insert(labels, index);
+            }
+        }
+        violations
+    }
+
+    /// This request, if it is valid, or [`RemoteWriteError::Validation`] with every violation.
+    pub fn validated(self) -> Result<Self, RemoteWriteError> {
+        let violations = self.validate();
+        if violations.is_empty() {
+            Ok(self)
+        } else {
+            Err(RemoteWriteError::Validation(violations))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Sample;
+    use pretty_assertions::assert_eq;
+    use prometheus::{Counter, Registry};
+
+    fn series(labels: &[(&str, &str)], timestamps: &[i64]) -> TimeSeries {
+        TimeSeries {
+            labels: labels
+                .iter()
+                .map(|(k, v)| Label {
+                    name: k.to_string(),
+                    value: v.to_string(),
+                })
+                .collect(),
+            samples: timestamps
+                .iter()
+                .map(|timestamp| Sample {
+                    value: 1.0,
+                    timestamp: *timestamp,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    pub fn converted_metrics_are_valid() {
+        let registry = Registry::new();
+        let counter = Counter::new("my_counter", "an extra description").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        let req = WriteRequest::from_metric_families(registry.gather(), None).unwrap();
+        assert_eq!(req.validate(), vec![]);
+        assert!(req.validated().is_ok());
+    }
+
+    #[test]
+    pub fn reports_every_violation() {
+        let req = WriteRequest {
+            timeseries: vec![
+                series(&[("job", "api")], &[1]),
+                series(&[(LABEL_NAME, "up"), ("job", "a"), ("job", "b")], &[1]),
+                series(&[(LABEL_NAME, "up"), ("job", "")], &[1]),
+                series(&[(LABEL_NAME, "0up"), ("job-name", "api")], &[1]),
+                series(&[("job", "api"), (LABEL_NAME, "up")], &[2, 2]),
+                series(&[(LABEL_NAME, "up"), ("job", "api")], &[1]),
+            ],
+            metadata: vec![],
+        };
+        assert_eq!(
+            req.validate(),
+            vec![
+                Violation::MissingMetricName { series: 0 },
+                Violation::DuplicateLabelName {
+                    series: 1,
+                    name: "job".to_string()
+                },
+                Violation::EmptyLabelValue {
+                    series: 2,
+                    name: "job".to_string()
+                },
+                Violation::InvalidMetricName {
+                    series: 3,
+                    name: "0up".to_string()
+                },
+                Violation::InvalidLabelName {
+                    series: 3,
+                    name: "job-name".to_string()
+                },
+                Violation::UnsortedLabels { series: 4 },
+                Violation::NonIncreasingTimestamps { series: 4 },
+                Violation::DuplicateSeries {
+                    series: 5,
+                    first: 4
+                },
+            ]
+        );
+        assert!(matches!(
+            req.validated(),
+            Err(RemoteWriteError::Validation(v)) if v.len() == 8
+        ));
+    }
+}